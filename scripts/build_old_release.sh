@@ -0,0 +1,27 @@
+#!/usr/bin/env bash
+# Builds the wasm of a released version for the migration tests
+# usage: scripts/build_old_release.sh [version] [git revision]
+set -euo pipefail
+
+VERSION="${1:-v0.5.0}"
+# v0.5.0 was never tagged, this is the commit it was released from
+REV="${2:-1e9809036bbf64f9b7c4a3cab42bb4a5e0ca32ee}"
+ROOT="$(git rev-parse --show-toplevel)"
+OUT="$ROOT/artifacts/banana_vault_$VERSION.wasm"
+WORKTREE="$(mktemp -d)"
+
+cleanup() {
+  git -C "$ROOT" worktree remove --force "$WORKTREE"
+}
+trap cleanup EXIT
+
+git -C "$ROOT" worktree add --detach "$WORKTREE" "$REV"
+
+docker run --rm -v "$WORKTREE":/code \
+  --mount type=volume,source="banana_vault_${VERSION}_cache",target=/target \
+  --mount type=volume,source=registry_cache,target=/usr/local/cargo/registry \
+  cosmwasm/optimizer:0.15.1
+
+mkdir -p "$ROOT/artifacts"
+cp "$WORKTREE/artifacts/banana_vault.wasm" "$OUT"
+echo "built $OUT"
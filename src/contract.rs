@@ -1,24 +1,33 @@
 use crate::{
     error::ContractError,
+    migrations::run_migrations,
     msg::{
-        AccountQuery, AccountResponse, DepositMsg, DepositQuery, Environment, ExecuteMsg,
-        InstantiateMsg, MigrateMsg, ModifyMsg, PositionMsg, QueryMsg, RewardQuery, State,
-        StateQuery, Swap, VaultMsg, WhitelistResponse,
+        AccountQuery, AccountResponse, AddressCap, Allocation, DepositMsg, DepositQuery,
+        Environment, ExecuteMsg, InstantiateMsg, MigrateMsg, ModifyMsg, PositionMsg, PriceSource,
+        QueryMsg, QueuedChangeResponse, RewardQuery, RoleAssignment, RolesResponse, State,
+        StateQuery, Swap, TwapKind, VaultAsset, VaultMsg, WhitelistResponse,
     },
     state::{
-        Config, ACCOUNTS_PENDING_BURN, ACCOUNTS_PENDING_MINT, ASSETS_PENDING_MINT, CAP_REACHED,
-        COMMISSION_RATE, COMMISSION_REWARDS, CONFIG, HALTED, LAST_UPDATE, OPERATOR, OWNER, POOL_ID,
-        POSITION_OPEN, SUPPLY, TERMINATED, UNCOMPOUNDED_REWARDS, VAULT_ASSETS, VAULT_DENOM,
-        WHITELISTED_DEPOSITORS,
+        CommissionReceiver, Config, DepositPolicy, FeeDestination, FillOrder, Metadata, MockPrice,
+        Numeraire, Pauses, PendingCommission, PendingOwner, PriceBound, PriceSnapshot,
+        QueuedChange, Role, TimelockedChange, ACCOUNTS_PENDING_BURN, ACCOUNTS_PENDING_MINT,
+        ADDRESS_CAPS, ASSETS_PENDING_MINT, BALANCES_SNAPSHOT, CAP_REACHED, COMMISSION_RATE,
+        COMMISSION_RECEIVERS, COMMISSION_REWARDS, CONFIG, DEPOSITED_DOLLARS, DEPOSIT_MERKLE_ROOT,
+        LAST_FEE_ACCRUAL, LAST_UPDATE, LAST_VAULT_PRICE, LOCKED_SHARES, MAX_COMMISSION,
+        MINT_QUEUE_POSITIONS, MOCK_PRICES, NEXT_MINT_QUEUE_POSITION, OPERATOR, OWNER, PAUSED,
+        PENDING_ALLOCATIONS, PENDING_COMMISSION, PENDING_OWNER, POOL_ID, POSITION_OPEN, ROLES,
+        SUPPLY, TERMINATED, TIMELOCK_NEXT_ID, TIMELOCK_QUEUE, TRACKED_BALANCES,
+        UNCOMPOUNDED_REWARDS, VAULT_ASSETS, VAULT_DENOM, WHITELISTED_DEPOSITORS,
     },
 };
 use cosmwasm_std::{
     attr, coin, entry_point, to_json_binary, Addr, Attribute, BankMsg, Binary, Coin, Coins,
-    CosmosMsg, Decimal, Deps, DepsMut, Empty, Env, MessageInfo, Order, QuerierWrapper, Response,
-    StdError, StdResult, Storage, Uint128,
+    CosmosMsg, Decimal, Decimal256, Deps, DepsMut, Empty, Env, MessageInfo, Order, Reply, ReplyOn,
+    Response, StdError, StdResult, Storage, SubMsg, Uint128, Uint256, WasmMsg,
 };
 use cw2::{get_contract_version, set_contract_version};
 use cw_storage_plus::Bound;
+use osmosis_std::shim::Timestamp;
 use osmosis_std::types::osmosis::{
     concentratedliquidity::v1beta1::{
         ConcentratedliquidityQuerier, FullPositionBreakdown, MsgAddToPosition,
@@ -26,13 +35,18 @@ use osmosis_std::types::osmosis::{
         UserPositionsResponse,
     },
     poolmanager::v1beta1::{MsgSplitRouteSwapExactAmountIn, PoolmanagerQuerier},
-    tokenfactory::v1beta1::{MsgBurn, MsgCreateDenom, MsgMint},
+    tokenfactory::v1beta1::{MsgBurn, MsgCreateDenom, MsgMint, MsgSetDenomMetadata},
+    twap::v1beta1::TwapQuerier,
 };
 use osmosis_std::types::{
+    cosmos::bank::v1beta1::{DenomUnit, Metadata as BankMetadata},
     cosmos::base::v1beta1::Coin as CosmosCoin,
     osmosis::concentratedliquidity::v1beta1::MsgWithdrawPosition,
 };
-use pyth_sdk_cw::{query_price_feed, PriceIdentifier};
+use pyth_sdk_cw::{
+    get_update_fee, query_price_feed, ExecuteMsg as PythExecuteMsg, PriceIdentifier,
+};
+use sha2::{Digest, Sha256};
 use std::str::FromStr;
 
 // version info for migration info
@@ -46,14 +60,62 @@ const PYTH_TESTNET_CONTRACT_ADDRESS: &str =
     "osmo1hpdzqku55lmfmptpyj6wdlugqs5etr6teqf7r4yqjjrxjznjhtuqqu5kdh";
 const PYTH_MAINNET_CONTRACT_ADDRESS: &str =
     "osmo13ge29x4e2s63a8ytz2px8gurtyznmue4a69n5275692v3qn3ks8q7cwck7";
-const PYTH_DUMMY_CONTRACT_ADDRESS: &str = "osmo1qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqmcn030";
-
+pub(crate) const PYTH_DUMMY_CONTRACT_ADDRESS: &str = "osmo1qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqmcn030";
+
+// Mock oracle prices test vaults start with, with an exponent of -8
+const MOCK_PRICE_SEEDS: [(&str, u128); 3] = [
+    // OSMO
+    (
+        "5867f5683c757393a0670ef0f701490950fe93fdb006d181c8265a831ac0c5c6",
+        164_243_925,
+    ),
+    // ATOM
+    (
+        "b00b60f88b03a6a625a8d1c048c3f66653edf217439983d037e7222c4e612819",
+        1_031_081_328,
+    ),
+    // ETH
+    (
+        "ff61491a931112ddf1bd8147cd1b641375f79f5825126d665480874634fd0ace",
+        278_558_964_008,
+    ),
+];
+
+// Longest subdenom the tokenfactory module accepts
+const MAX_SUBDENOM_LENGTH: usize = 44;
+
+// Default subdenom of the vault token
 const BASE_DENOM: &str = "BVT";
 
-const DEC_18: u128 = 1_000_000_000_000_000_000;
+// Default decimals of the displayed vault token unit
+const DEFAULT_EXPONENT: u32 = 18;
+
+// Dollar amounts are in units of 10^-26 USD, so that assets with many decimals keep their precision
+const DOLLAR_EXPONENT: i32 = 26;
+
+// Dollar value of one unit of vault token at instantiation, so that 10^18 units are worth 1 USD
+const INITIAL_VAULT_PRICE: u128 = 100_000_000;
+
+// Vault tokens minted to the contract at instantiation and never burned, so the supply can't go back to zero
+pub(crate) const DEAD_SHARES: u128 = 1_000_000_000_000;
+
+// Virtual shares, worth the initial vault price, added when pricing shares. Donations can't move the
+// share price enough for rounding to take value from depositors
+const VIRTUAL_SHARES: u128 = 1_000_000_000_000;
+
+// The amount of time that can pass between operator updates, before the dead man switch is active
+pub(crate) const DEFAULT_UPDATE_INTERVAL: u64 = 86400 * 14; // 14 days
+const MIN_UPDATE_INTERVAL: u64 = 86400; // 1 day
+const MAX_UPDATE_INTERVAL: u64 = 86400 * 90; // 90 days
+
+// Shortest notice holders get before a commission increase applies
+pub(crate) const MIN_COMMISSION_DELAY: u64 = 86400; // 1 day
 
-// The maximum amount of time that can pass between updates, before the dead man switch is active
-const MAX_UPDATE_INTERVAL: u64 = 86400 * 14; // 14 days
+// Shortest notice holders get before a timelocked change applies
+pub(crate) const MIN_TIMELOCK_DELAY: u64 = 86400; // 1 day
+
+// Used to prorate the annualized management fee
+const SECONDS_PER_YEAR: u64 = 86400 * 365;
 
 // Default minimum amount of coins that can be burned at once
 const DEFAULT_MIN_REDEMPTION: Uint128 = Uint128::new(1_000_000);
@@ -61,6 +123,16 @@ const DEFAULT_MIN_REDEMPTION: Uint128 = Uint128::new(1_000_000);
 // Pagination
 const MAX_PAGE_LIMIT: u32 = 250;
 
+// Allocation tree domain separation, so an inner node can't pass as a leaf
+const MERKLE_LEAF_PREFIX: u8 = 0;
+const MERKLE_NODE_PREFIX: u8 = 1;
+
+// Reply resyncing the tracked balances after messages with unknown outcomes
+const SYNC_BALANCES_REPLY_ID: u64 = 1;
+// Replies processing entries and exits once the pushed price updates went through
+const PROCESS_MINTS_REPLY_ID: u64 = 2;
+const PROCESS_BURNS_REPLY_ID: u64 = 3;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
@@ -77,11 +149,23 @@ pub fn instantiate(
     deps.api.addr_validate(msg.operator.as_str())?;
     OPERATOR.save(deps.storage, &msg.operator)?;
 
-    let pyth_contract_address = match msg.env {
-        Some(Environment::Mainnet) | None => PYTH_MAINNET_CONTRACT_ADDRESS,
-        Some(Environment::Testnet) => PYTH_TESTNET_CONTRACT_ADDRESS,
-        Some(Environment::Testtube) => PYTH_DUMMY_CONTRACT_ADDRESS,
+    // an explicit address takes precedence over the environment
+    let pyth_contract_address = match (msg.pyth_contract_address, &msg.env) {
+        (Some(address), _) => address,
+        (None, Some(Environment::Mainnet) | None) => Addr::unchecked(PYTH_MAINNET_CONTRACT_ADDRESS),
+        (None, Some(Environment::Testnet)) => Addr::unchecked(PYTH_TESTNET_CONTRACT_ADDRESS),
+        (None, Some(Environment::Testtube)) => Addr::unchecked(PYTH_DUMMY_CONTRACT_ADDRESS),
     };
+    // the mock oracle can only be picked through the test environment
+    if msg.env != Some(Environment::Testtube)
+        || pyth_contract_address != PYTH_DUMMY_CONTRACT_ADDRESS
+    {
+        verify_oracle_address(&deps.as_ref(), &pyth_contract_address)?;
+    }
+
+    if pyth_contract_address == PYTH_DUMMY_CONTRACT_ADDRESS {
+        seed_mock_prices(deps.storage)?;
+    }
 
     let config = Config {
         metadata: msg.metadata,
@@ -89,13 +173,36 @@ pub fn instantiate(
         min_asset1: msg.min_asset1,
         min_redemption: msg.min_redemption,
         dollar_cap: msg.dollar_cap,
-        pyth_contract_address: Addr::unchecked(pyth_contract_address),
+        address_cap: msg.address_cap,
+        pyth_contract_address,
         price_expiry: msg.price_expiry,
-        commission_receiver: msg
-            .commission_receiver
-            .unwrap_or_else(|| info.sender.clone()),
+        max_confidence_ratio: msg.max_confidence_ratio,
+        price_bound: msg.price_bound.unwrap_or(PriceBound::Mid),
+        numeraire: msg.numeraire.unwrap_or(Numeraire::Usd),
+        management_fee: msg.management_fee,
+        deposit_fee: msg.deposit_fee,
+        withdraw_fee: msg.withdraw_fee,
+        fee_destination: msg.fee_destination,
+        commission_delay: msg.commission_delay,
+        timelock_delay: msg.timelock_delay,
+        circuit_breaker: msg.circuit_breaker,
+        deposit_policy: msg.deposit_policy.unwrap_or(DepositPolicy::CapBypassOnly),
+        fill_order: msg.fill_order.unwrap_or(FillOrder::FirstCome),
+        refund_unfilled: msg.refund_unfilled.unwrap_or_default(),
+        update_interval: msg.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL),
     };
 
+    verify_config(&config)?;
+
+    for asset in [&msg.asset0, &msg.asset1] {
+        for source in [&asset.oracle, &asset.fallback].into_iter().flatten() {
+            verify_price_source(source, &asset.denom)?;
+        }
+    }
+    if let Numeraire::External { denom, source, .. } = &config.numeraire {
+        verify_price_source(source, denom)?;
+    }
+
     // Check that the pool is the correct type and has the correct assets
     verify_pool(
         &deps.as_ref(),
@@ -108,7 +215,7 @@ pub fn instantiate(
     VAULT_ASSETS.save(deps.storage, &(msg.asset0.clone(), msg.asset1.clone()))?;
 
     // Check that funds sent match with config
-    verify_mint_funds(
+    let seed = verify_mint_funds(
         &info.funds,
         msg.asset0.denom.clone(),
         msg.asset1.denom.clone(),
@@ -118,35 +225,62 @@ pub fn instantiate(
 
     CONFIG.save(deps.storage, &config)?;
 
-    let vault_denom = format!("factory/{}/{}", &env.contract.address, BASE_DENOM);
+    let subdenom = msg.subdenom.unwrap_or(BASE_DENOM.to_string());
+    // it is the default symbol too, so it has to be a valid denom on its own
+    if subdenom.len() > MAX_SUBDENOM_LENGTH || !is_valid_denom(&subdenom) {
+        return Err(ContractError::InvalidSubdenom { subdenom });
+    }
+    let vault_denom = format!("factory/{}/{}", &env.contract.address, subdenom);
     VAULT_DENOM.save(deps.storage, &vault_denom)?;
 
     // create the vault token
     let create_msg: CosmosMsg = MsgCreateDenom {
         sender: env.contract.address.clone().into_string(),
-        subdenom: BASE_DENOM.to_string(),
+        subdenom,
     }
     .into();
 
-    // todo: refine initial mint logic
-    // provisional logic
-    let initial_mint = Uint128::new(DEC_18);
+    let metadata_msg = set_denom_metadata(&env, &vault_denom, &config.metadata);
 
-    let mint_msg: CosmosMsg = MsgMint {
-        sender: env.contract.address.clone().into_string(),
-        amount: Some(osmosis_std::types::cosmos::base::v1beta1::Coin {
-            denom: vault_denom.clone(),
-            amount: initial_mint.to_string(),
-        }),
-        mint_to_address: info.sender.into_string(),
+    // the initial mint is the oracle value of the seed at the initial vault price
+    let (price0, price1) = get_asset_prices(&deps.as_ref(), &env, PriceUse::Mint)?;
+    let seed_dollars = Uint256::from(seed[0].amount)
+        .checked_mul(price0)?
+        .checked_add(Uint256::from(seed[1].amount).checked_mul(price1)?)?;
+    let initial_mint = Uint128::try_from(seed_dollars / Uint256::from(INITIAL_VAULT_PRICE))?;
+
+    // part of it stays locked in the contract
+    let dead_shares = Uint128::new(DEAD_SHARES);
+    if initial_mint <= dead_shares {
+        return Err(ContractError::SeedTooSmall);
     }
-    .into();
+
+    let mint_msgs: Vec<CosmosMsg> = [
+        (info.sender.to_string(), initial_mint - dead_shares),
+        (env.contract.address.to_string(), dead_shares),
+    ]
+    .into_iter()
+    .map(|(mint_to_address, amount)| {
+        MsgMint {
+            sender: env.contract.address.clone().into_string(),
+            amount: Some(osmosis_std::types::cosmos::base::v1beta1::Coin {
+                denom: vault_denom.clone(),
+                amount: amount.to_string(),
+            }),
+            mint_to_address,
+        }
+        .into()
+    })
+    .collect();
 
     SUPPLY.save(deps.storage, &initial_mint)?;
+    LOCKED_SHARES.save(deps.storage, &dead_shares)?;
+    TRACKED_BALANCES.save(deps.storage, &seed)?;
     LAST_UPDATE.save(deps.storage, &env.block.time.seconds())?;
+    LAST_FEE_ACCRUAL.save(deps.storage, &env.block.time.seconds())?;
 
     CAP_REACHED.save(deps.storage, &false)?;
-    HALTED.save(deps.storage, &false)?;
+    PAUSED.save(deps.storage, &Pauses::default())?;
     TERMINATED.save(deps.storage, &false)?;
     POSITION_OPEN.save(deps.storage, &false)?;
 
@@ -158,10 +292,27 @@ pub fn instantiate(
         ],
     )?;
 
-    if msg.commission.unwrap_or_default() >= Decimal::percent(100) {
+    let commission = msg.commission.unwrap_or_default();
+    let max_commission = msg.max_commission.unwrap_or(commission);
+    if max_commission >= Decimal::percent(100) {
         return Err(ContractError::CommissionTooHigh);
     }
-    COMMISSION_RATE.save(deps.storage, &msg.commission.unwrap_or_default())?;
+    if commission > max_commission {
+        return Err(ContractError::CommissionAboveMax {
+            max: max_commission.to_string(),
+        });
+    }
+    COMMISSION_RATE.save(deps.storage, &commission)?;
+    MAX_COMMISSION.save(deps.storage, &max_commission)?;
+
+    let commission_receivers = msg.commission_receivers.unwrap_or_else(|| {
+        vec![CommissionReceiver {
+            address: info.sender.clone(),
+            weight: Decimal::one(),
+        }]
+    });
+    verify_commission_receivers(&deps.as_ref(), &commission_receivers)?;
+    COMMISSION_RECEIVERS.save(deps.storage, &commission_receivers)?;
     COMMISSION_REWARDS.save(
         deps.storage,
         &vec![coin(0, msg.asset0.denom), coin(0, msg.asset1.denom)],
@@ -171,7 +322,8 @@ pub fn instantiate(
 
     Ok(Response::new()
         .add_message(create_msg)
-        .add_message(mint_msg)
+        .add_message(metadata_msg)
+        .add_messages(mint_msgs)
         .add_attribute("action", "banana_vault_instantiate")
         .add_attribute("denom", vault_denom)
         .add_attribute("contract_name", CONTRACT_NAME)
@@ -181,44 +333,146 @@ pub fn instantiate(
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     info: MessageInfo,
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::ManageVault(admin_msg) => {
-            if info.sender != OWNER.load(deps.storage)?
-                && info.sender != OPERATOR.load(deps.storage)?
-            {
-                return Err(ContractError::Unauthorized);
+            match &admin_msg {
+                // checked against the pending owner instead
+                VaultMsg::Modify(ModifyMsg::AcceptOwnership) => {}
+                VaultMsg::Modify(ModifyMsg::Whitelist { .. }) => {
+                    assert_role(deps.storage, &info.sender, Role::WhitelistManager)?
+                }
+                VaultMsg::Modify(_) | VaultMsg::Resume(_) | VaultMsg::SetMockPrices(_) => {
+                    assert_owner(deps.storage, &info.sender)?
+                }
+                VaultMsg::CompoundRewards(_) => {
+                    assert_role(deps.storage, &info.sender, Role::Strategist)?
+                }
+                VaultMsg::CollectCommission | VaultMsg::AccrueFees => {
+                    assert_role(deps.storage, &info.sender, Role::FeeCollector)?
+                }
+                VaultMsg::ProcessMints
+                | VaultMsg::ProcessBurns
+                | VaultMsg::ProcessMintsWithPriceUpdates { .. }
+                | VaultMsg::ProcessBurnsWithPriceUpdates { .. } => {
+                    assert_role(deps.storage, &info.sender, Role::Processor)?
+                }
+                VaultMsg::Pause(_) => assert_role(deps.storage, &info.sender, Role::Guardian)?,
+                VaultMsg::Heartbeat | VaultMsg::AbsorbDonations => {
+                    if info.sender != OPERATOR.load(deps.storage)? {
+                        return Err(ContractError::Unauthorized);
+                    }
+                }
             }
             match admin_msg {
                 VaultMsg::Modify(modify_msg) => match modify_msg {
-                    ModifyMsg::Operator(operator) => execute_modify_operator(deps, &operator),
-                    ModifyMsg::Config(config) => execute_modify_config(deps, &env, &config),
-                    ModifyMsg::PoolId(pool_id) => execute_modify_pool_id(deps, pool_id),
+                    ModifyMsg::TransferOwnership { new_owner, expiry } => {
+                        execute_transfer_ownership(deps, &env, &new_owner, expiry)
+                    }
+                    ModifyMsg::AcceptOwnership => execute_accept_ownership(deps, &env, &info),
+                    ModifyMsg::RenounceOwnership => execute_renounce_ownership(deps),
+                    ModifyMsg::Operator(operator) => {
+                        execute_timelocked_change(deps, &env, TimelockedChange::Operator(operator))
+                    }
+                    // strategists move the vault funds like the operator, so they wait out the timelock too
+                    ModifyMsg::GrantRole {
+                        address,
+                        role: Role::Strategist,
+                    } => execute_timelocked_change(
+                        deps,
+                        &env,
+                        TimelockedChange::GrantRole {
+                            address,
+                            role: Role::Strategist,
+                        },
+                    ),
+                    ModifyMsg::GrantRole { address, role } => {
+                        execute_grant_role(deps, address, role)
+                    }
+                    ModifyMsg::RevokeRole { address, role } => {
+                        execute_revoke_role(deps, address, role)
+                    }
+                    ModifyMsg::Config(config) => {
+                        let changes = CONFIG.load(deps.storage)?.changes_to(&config)?;
+                        execute_timelocked_change(deps, &env, TimelockedChange::Config(changes))
+                    }
+                    ModifyMsg::PoolId(pool_id) => {
+                        execute_timelocked_change(deps, &env, TimelockedChange::PoolId(pool_id))
+                    }
+                    ModifyMsg::ExecuteQueued(id) => execute_queued_change(deps, &env, id),
+                    ModifyMsg::CancelQueued(id) => execute_cancel_queued_change(deps, id),
                     ModifyMsg::Commission(commission) => {
-                        execute_modify_commission(deps, commission)
+                        execute_modify_commission(deps, &env, commission)
+                    }
+                    ModifyMsg::ApplyCommission => execute_apply_commission(deps, &env),
+                    ModifyMsg::CommissionReceivers(receivers) => {
+                        execute_modify_commission_receivers(deps, receivers)
                     }
                     ModifyMsg::Whitelist { add, remove } => execute_whitelist(deps, add, remove),
+                    ModifyMsg::AddressCaps { set, remove } => {
+                        execute_modify_address_caps(deps, set, remove)
+                    }
+                    ModifyMsg::DepositMerkleRoot(root) => {
+                        execute_modify_deposit_merkle_root(deps, root)
+                    }
                 },
                 VaultMsg::CompoundRewards(swap) => execute_compound_rewards(deps, &env, swap),
                 VaultMsg::CollectCommission => execute_collect_commission(deps),
-                VaultMsg::ProcessMints => execute_process_mints(deps, &env),
-                VaultMsg::ProcessBurns => execute_process_burns(deps, &env),
-                VaultMsg::Halt => execute_halt(deps),
-                VaultMsg::Resume => execute_resume(deps),
+                VaultMsg::AccrueFees => execute_accrue_fees(deps, &env),
+                VaultMsg::ProcessMints => {
+                    refresh_last_update(deps.storage, &env, &info.sender)?;
+                    assert_no_funds(&info)?;
+                    execute_process_mints(deps, &env)
+                }
+                VaultMsg::ProcessBurns => {
+                    refresh_last_update(deps.storage, &env, &info.sender)?;
+                    assert_no_funds(&info)?;
+                    execute_process_burns(deps, &env)
+                }
+                VaultMsg::ProcessMintsWithPriceUpdates { price_updates } => {
+                    refresh_last_update(deps.storage, &env, &info.sender)?;
+                    match price_updates_to_push(deps.storage, price_updates)? {
+                        Some(updates) => {
+                            execute_push_price_updates(deps, &info, updates, PROCESS_MINTS_REPLY_ID)
+                        }
+                        None => {
+                            assert_no_funds(&info)?;
+                            execute_process_mints(deps, &env)
+                        }
+                    }
+                }
+                VaultMsg::ProcessBurnsWithPriceUpdates { price_updates } => {
+                    refresh_last_update(deps.storage, &env, &info.sender)?;
+                    match price_updates_to_push(deps.storage, price_updates)? {
+                        Some(updates) => {
+                            execute_push_price_updates(deps, &info, updates, PROCESS_BURNS_REPLY_ID)
+                        }
+                        None => {
+                            assert_no_funds(&info)?;
+                            execute_process_burns(deps, &env)
+                        }
+                    }
+                }
+                VaultMsg::Heartbeat => execute_heartbeat(deps, &env),
+                VaultMsg::AbsorbDonations => execute_absorb_donations(deps, &env),
+                VaultMsg::Pause(pauses) => execute_pause(deps, &pauses),
+                VaultMsg::Resume(pauses) => execute_resume(deps, &pauses),
+                VaultMsg::SetMockPrices(prices) => execute_set_mock_prices(deps, prices),
             }
         }
         ExecuteMsg::ManagePosition(position_msg) => {
-            if info.sender != OPERATOR.load(deps.storage)? {
-                return Err(ContractError::Unauthorized);
-            }
+            assert_role(deps.storage, &info.sender, Role::Strategist)?;
             if TERMINATED.load(deps.storage)? {
                 return Err(ContractError::VaultClosed);
             }
-            match position_msg {
+            assert_not_paused(deps.storage, |p| p.positions, "positions")?;
+            refresh_last_update(deps.storage, &env, &info.sender)?;
+            let untracked = get_untracked_balances(&deps.as_ref(), &env)?;
+            let response = match position_msg {
                 PositionMsg::CreatePosition {
                     lower_tick,
                     upper_tick,
@@ -227,7 +481,7 @@ pub fn execute(
                     token_min_amount1,
                     swap,
                 } => execute_create_position(
-                    deps,
+                    deps.branch(),
                     &env,
                     lower_tick,
                     upper_tick,
@@ -245,7 +499,7 @@ pub fn execute(
                     swap,
                     override_uptime,
                 } => execute_add_to_position(
-                    deps,
+                    deps.branch(),
                     env,
                     position_id,
                     amount0,
@@ -260,16 +514,20 @@ pub fn execute(
                     liquidity_amount,
                     override_uptime,
                 } => execute_withdraw_position(
-                    deps,
+                    deps.branch(),
                     &env,
                     position_id,
                     liquidity_amount,
                     override_uptime,
                 ),
-            }
+            }?;
+            Ok(sync_balances_after(deps.storage, untracked, response)?)
         }
         ExecuteMsg::Deposit(deposit_msg) => match deposit_msg {
-            DepositMsg::Mint { min_out } => execute_deposit_for_mint(deps, &info, &min_out),
+            DepositMsg::Mint {
+                min_out,
+                allocation,
+            } => execute_deposit_for_mint(deps, &env, &info, &min_out, allocation),
             DepositMsg::Burn { address, amount } => {
                 execute_deposit_for_burn(deps, &env, &info, address, amount)
             }
@@ -278,6 +536,175 @@ pub fn execute(
     }
 }
 
+fn execute_transfer_ownership(
+    deps: DepsMut,
+    env: &Env,
+    new_owner: &Addr,
+    expiry: Option<u64>,
+) -> Result<Response, ContractError> {
+    deps.api.addr_validate(new_owner.as_str())?;
+
+    if expiry.is_some_and(|expiry| expiry <= env.block.time.seconds()) {
+        return Err(ContractError::OwnershipTransferExpired);
+    }
+
+    PENDING_OWNER.save(
+        deps.storage,
+        &PendingOwner {
+            address: new_owner.clone(),
+            expiry,
+        },
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "banana_vault_transfer_ownership")
+        .add_attribute("pending_owner", new_owner))
+}
+
+fn execute_accept_ownership(
+    deps: DepsMut,
+    env: &Env,
+    info: &MessageInfo,
+) -> Result<Response, ContractError> {
+    let pending_owner = PENDING_OWNER
+        .may_load(deps.storage)?
+        .ok_or(ContractError::NoPendingOwner)?;
+
+    if info.sender != pending_owner.address {
+        return Err(ContractError::Unauthorized);
+    }
+
+    if pending_owner
+        .expiry
+        .is_some_and(|expiry| expiry <= env.block.time.seconds())
+    {
+        return Err(ContractError::OwnershipTransferExpired);
+    }
+
+    OWNER.save(deps.storage, &info.sender)?;
+    PENDING_OWNER.remove(deps.storage);
+
+    Ok(Response::new()
+        .add_attribute("action", "banana_vault_accept_ownership")
+        .add_attribute("new_owner", info.sender.to_string()))
+}
+
+fn execute_renounce_ownership(deps: DepsMut) -> Result<Response, ContractError> {
+    OWNER.remove(deps.storage);
+    PENDING_OWNER.remove(deps.storage);
+
+    Ok(Response::new().add_attribute("action", "banana_vault_renounce_ownership"))
+}
+
+// Sensitive changes are queued until the timelock delay has passed
+fn execute_timelocked_change(
+    deps: DepsMut,
+    env: &Env,
+    change: TimelockedChange,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let mut timelock_delay = config.timelock_delay;
+
+    // fail early on changes that could never be applied
+    match &change {
+        TimelockedChange::Operator(operator) => {
+            deps.api.addr_validate(operator.as_str())?;
+        }
+        TimelockedChange::Config(changes) => {
+            if changes.is_empty() {
+                return Err(ContractError::EmptyConfigChange);
+            }
+
+            let mut new_config = config.clone();
+            new_config.apply_changes(changes)?;
+
+            // caps, deposited values and price snapshots are all in the current numeraire
+            if new_config.numeraire != config.numeraire {
+                return Err(ContractError::CannotChangeNumeraire);
+            }
+            if new_config.pyth_contract_address != config.pyth_contract_address {
+                verify_oracle_address(&deps.as_ref(), &new_config.pyth_contract_address)?;
+            }
+            verify_config(&new_config)?;
+
+            // lowering the commission delay waits out the current one, so holders keep their exit window
+            if new_config.commission_delay < config.commission_delay {
+                timelock_delay = timelock_delay.max(config.commission_delay);
+            }
+        }
+        TimelockedChange::GrantRole { address, .. } => {
+            deps.api.addr_validate(address.as_str())?;
+        }
+        TimelockedChange::PoolId(pool_id) => {
+            let vault_assets = VAULT_ASSETS.load(deps.storage)?;
+            verify_pool(
+                &deps.as_ref(),
+                *pool_id,
+                vault_assets.0.denom,
+                vault_assets.1.denom,
+            )?;
+        }
+    }
+
+    let id = TIMELOCK_NEXT_ID.may_load(deps.storage)?.unwrap_or_default();
+    TIMELOCK_NEXT_ID.save(deps.storage, &(id + 1))?;
+
+    let eta = env.block.time.seconds() + timelock_delay;
+    TIMELOCK_QUEUE.save(deps.storage, id, &QueuedChange { change, eta })?;
+
+    Ok(Response::new()
+        .add_attribute("action", "banana_vault_queue_change")
+        .add_attribute("id", id.to_string())
+        .add_attribute("eta", eta.to_string()))
+}
+
+fn execute_queued_change(deps: DepsMut, env: &Env, id: u64) -> Result<Response, ContractError> {
+    let queued = TIMELOCK_QUEUE
+        .may_load(deps.storage, id)?
+        .ok_or(ContractError::QueuedChangeNotFound { id })?;
+
+    if env.block.time.seconds() < queued.eta {
+        return Err(ContractError::QueuedChangeNotReady {
+            seconds: queued.eta - env.block.time.seconds(),
+        });
+    }
+
+    TIMELOCK_QUEUE.remove(deps.storage, id);
+
+    Ok(apply_timelocked_change(deps, env, queued.change)?
+        .add_attribute("queued_change", id.to_string()))
+}
+
+fn execute_cancel_queued_change(deps: DepsMut, id: u64) -> Result<Response, ContractError> {
+    if !TIMELOCK_QUEUE.has(deps.storage, id) {
+        return Err(ContractError::QueuedChangeNotFound { id });
+    }
+
+    TIMELOCK_QUEUE.remove(deps.storage, id);
+
+    Ok(Response::new()
+        .add_attribute("action", "banana_vault_cancel_queued_change")
+        .add_attribute("id", id.to_string()))
+}
+
+fn apply_timelocked_change(
+    deps: DepsMut,
+    env: &Env,
+    change: TimelockedChange,
+) -> Result<Response, ContractError> {
+    match change {
+        TimelockedChange::Operator(operator) => execute_modify_operator(deps, &operator),
+        TimelockedChange::Config(changes) => {
+            // only the queued fields change, anything changed since it was queued stays
+            let mut new_config = CONFIG.load(deps.storage)?;
+            new_config.apply_changes(&changes)?;
+            execute_modify_config(deps, env, &new_config)
+        }
+        TimelockedChange::GrantRole { address, role } => execute_grant_role(deps, address, role),
+        TimelockedChange::PoolId(pool_id) => execute_modify_pool_id(deps, pool_id),
+    }
+}
+
 fn execute_modify_operator(deps: DepsMut, new_operator: &Addr) -> Result<Response, ContractError> {
     deps.api.addr_validate(new_operator.as_str())?;
     OPERATOR.save(deps.storage, new_operator)?;
@@ -287,28 +714,103 @@ fn execute_modify_operator(deps: DepsMut, new_operator: &Addr) -> Result<Respons
         .add_attribute("new_operator", new_operator))
 }
 
+fn execute_grant_role(deps: DepsMut, address: Addr, role: Role) -> Result<Response, ContractError> {
+    deps.api.addr_validate(address.as_str())?;
+
+    let mut roles = ROLES
+        .may_load(deps.storage, address.clone())?
+        .unwrap_or_default();
+
+    if roles.contains(&role) {
+        return Err(ContractError::RoleAlreadyGranted {
+            address: address.to_string(),
+            role: format!("{role:?}"),
+        });
+    }
+
+    roles.push(role.clone());
+    ROLES.save(deps.storage, address.clone(), &roles)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "banana_vault_grant_role")
+        .add_attribute("address", address)
+        .add_attribute("role", format!("{role:?}")))
+}
+
+fn execute_revoke_role(
+    deps: DepsMut,
+    address: Addr,
+    role: Role,
+) -> Result<Response, ContractError> {
+    let mut roles = ROLES
+        .may_load(deps.storage, address.clone())?
+        .unwrap_or_default();
+
+    if !roles.contains(&role) {
+        return Err(ContractError::RoleNotGranted {
+            address: address.to_string(),
+            role: format!("{role:?}"),
+        });
+    }
+
+    roles.retain(|r| *r != role);
+    if roles.is_empty() {
+        ROLES.remove(deps.storage, address.clone());
+    } else {
+        ROLES.save(deps.storage, address.clone(), &roles)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "banana_vault_revoke_role")
+        .add_attribute("address", address)
+        .add_attribute("role", format!("{role:?}")))
+}
+
 fn execute_modify_config(
     deps: DepsMut,
     env: &Env,
     new_config: &Config,
 ) -> Result<Response, ContractError> {
-    deps.api
-        .addr_validate(new_config.pyth_contract_address.as_str())?;
+    if new_config.pyth_contract_address != CONFIG.load(deps.storage)?.pyth_contract_address {
+        verify_oracle_address(&deps.as_ref(), &new_config.pyth_contract_address)?;
+    }
+
+    verify_config(new_config)?;
+
+    // settle the fee accrued so far at the old rate before it changes
+    let (messages, mut attributes) = accrue_management_fee(deps.storage, env)?;
 
     if let Some(new_dollar_cap) = new_config.dollar_cap {
         let (asset0, asset1) =
             get_vault_balances(&deps.as_ref(), &env.contract.address.to_string(), true)?;
 
-        let pricing = get_vault_pricing(&deps.as_ref(), env, &asset0.amount, &asset1.amount)?;
+        let pricing = get_vault_pricing(
+            &deps.as_ref(),
+            env,
+            &asset0.amount,
+            &asset1.amount,
+            PriceUse::Valuation,
+        )?;
 
-        CAP_REACHED.save(deps.storage, &(pricing.total_dollars >= new_dollar_cap))?;
+        CAP_REACHED.save(
+            deps.storage,
+            &(pricing.total_dollars >= Uint256::from(new_dollar_cap)),
+        )?;
     } else {
         CAP_REACHED.save(deps.storage, &false)?;
     }
 
     CONFIG.save(deps.storage, new_config)?;
 
-    Ok(Response::new().add_attribute("action", "banana_vault_modify_config"))
+    let metadata_msg =
+        set_denom_metadata(env, &VAULT_DENOM.load(deps.storage)?, &new_config.metadata);
+
+    attributes.push(attr("action", "banana_vault_modify_config"));
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_message(metadata_msg)
+        .add_attributes(attributes))
 }
 
 // We will allow the pool type to change as long as the assets are the same
@@ -335,16 +837,110 @@ fn execute_modify_pool_id(deps: DepsMut, new_pool_id: u64) -> Result<Response, C
 
 fn execute_modify_commission(
     deps: DepsMut,
+    env: &Env,
     new_commission: Decimal,
 ) -> Result<Response, ContractError> {
-    if new_commission >= Decimal::percent(100) {
-        return Err(ContractError::CommissionTooHigh);
+    let max_commission = MAX_COMMISSION.load(deps.storage)?;
+    if new_commission > max_commission {
+        return Err(ContractError::CommissionAboveMax {
+            max: max_commission.to_string(),
+        });
+    }
+
+    // a new proposal always replaces the pending one
+    PENDING_COMMISSION.remove(deps.storage);
+
+    // lowering the commission can only benefit depositors, so there is no need to wait
+    if new_commission <= COMMISSION_RATE.load(deps.storage)? {
+        COMMISSION_RATE.save(deps.storage, &new_commission)?;
+
+        return Ok(Response::new()
+            .add_attribute("action", "banana_vault_modify_commission")
+            .add_attribute("new_commission", new_commission.to_string()));
+    }
+
+    let eta = env.block.time.seconds() + CONFIG.load(deps.storage)?.commission_delay;
+    PENDING_COMMISSION.save(
+        deps.storage,
+        &PendingCommission {
+            rate: new_commission,
+            eta,
+        },
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "banana_vault_propose_commission")
+        .add_attribute("new_commission", new_commission.to_string())
+        .add_attribute("eta", eta.to_string()))
+}
+
+fn execute_apply_commission(deps: DepsMut, env: &Env) -> Result<Response, ContractError> {
+    let pending = PENDING_COMMISSION
+        .may_load(deps.storage)?
+        .ok_or(ContractError::NoPendingCommission)?;
+
+    if env.block.time.seconds() < pending.eta {
+        return Err(ContractError::CommissionNotReady {
+            seconds: pending.eta - env.block.time.seconds(),
+        });
     }
-    COMMISSION_RATE.save(deps.storage, &new_commission)?;
+
+    COMMISSION_RATE.save(deps.storage, &pending.rate)?;
+    PENDING_COMMISSION.remove(deps.storage);
 
     Ok(Response::new()
         .add_attribute("action", "banana_vault_modify_commission")
-        .add_attribute("new_commission", new_commission.to_string()))
+        .add_attribute("new_commission", pending.rate.to_string()))
+}
+
+fn execute_modify_commission_receivers(
+    deps: DepsMut,
+    receivers: Vec<CommissionReceiver>,
+) -> Result<Response, ContractError> {
+    verify_commission_receivers(&deps.as_ref(), &receivers)?;
+    COMMISSION_RECEIVERS.save(deps.storage, &receivers)?;
+
+    let mut attributes = vec![attr("action", "banana_vault_modify_commission_receivers")];
+    for receiver in receivers {
+        attributes.push(attr("receiver", receiver.address));
+        attributes.push(attr("weight", receiver.weight.to_string()));
+    }
+
+    Ok(Response::new().add_attributes(attributes))
+}
+
+fn execute_modify_deposit_merkle_root(
+    deps: DepsMut,
+    root: Option<String>,
+) -> Result<Response, ContractError> {
+    match root {
+        Some(root) => {
+            let mut root_buf: [u8; 32] = [0; 32];
+            hex::decode_to_slice(&root, &mut root_buf)
+                .map_err(|_| ContractError::InvalidMerkleProof)?;
+            DEPOSIT_MERKLE_ROOT.save(deps.storage, &root)?;
+        }
+        None => DEPOSIT_MERKLE_ROOT.remove(deps.storage),
+    }
+
+    Ok(Response::new().add_attribute("action", "banana_vault_modify_deposit_merkle_root"))
+}
+
+fn execute_modify_address_caps(
+    deps: DepsMut,
+    set: Option<Vec<AddressCap>>,
+    remove: Option<Vec<Addr>>,
+) -> Result<Response, ContractError> {
+    for address_cap in set.unwrap_or_default() {
+        deps.api.addr_validate(address_cap.address.as_str())?;
+        ADDRESS_CAPS.save(deps.storage, address_cap.address, &address_cap.cap)?;
+    }
+
+    for address in remove.unwrap_or_default() {
+        ADDRESS_CAPS.remove(deps.storage, address);
+    }
+
+    Ok(Response::new().add_attribute("action", "banana_vault_modify_address_caps"))
 }
 
 fn execute_whitelist(
@@ -387,6 +983,9 @@ fn execute_compound_rewards(
     env: &Env,
     swaps: Vec<Swap>,
 ) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, |p| p.compounding, "compounding")?;
+
+    let untracked = get_untracked_balances(&deps.as_ref(), env)?;
     let mut messages: Vec<CosmosMsg> = vec![];
     let mut rewards = Coins::try_from(UNCOMPOUNDED_REWARDS.load(deps.storage)?).unwrap_or_default();
     let commission_rate = COMMISSION_RATE.load(deps.storage)?;
@@ -443,39 +1042,48 @@ fn execute_compound_rewards(
         );
     }
 
-    messages.push(
-        BankMsg::Send {
-            to_address: CONFIG.load(deps.storage)?.commission_receiver.into_string(),
-            amount: commissions.into(),
-        }
-        .into(),
-    );
+    messages.extend(prepare_commission_payouts(
+        deps.storage,
+        commissions.into(),
+    )?);
 
     UNCOMPOUNDED_REWARDS.save(deps.storage, &rewards.to_vec())?;
 
-    Ok(Response::new()
-        .add_messages(messages)
-        .add_attribute("action", "banana_vault_compound_rewards"))
+    Ok(sync_balances_after(
+        deps.storage,
+        untracked,
+        Response::new()
+            .add_messages(messages)
+            .add_attribute("action", "banana_vault_compound_rewards"),
+    )?)
 }
 
 fn execute_deposit_for_mint(
     deps: DepsMut,
+    env: &Env,
     info: &MessageInfo,
     min_out: &Option<Uint128>,
+    allocation: Option<Allocation>,
 ) -> Result<Response, ContractError> {
     // Check if vault is closed
     if TERMINATED.load(deps.storage)? {
         return Err(ContractError::VaultClosed);
     }
 
-    // Check if vault is halted
-    if HALTED.load(deps.storage)? {
-        return Err(ContractError::VaultHalted);
+    // Check if mints are paused
+    assert_not_paused(deps.storage, |p| p.mints, "mints")?;
+
+    let whitelisted = WHITELISTED_DEPOSITORS.has(deps.storage, info.sender.clone());
+    let deposit_policy = CONFIG.load(deps.storage)?.deposit_policy;
+
+    // Check if the vault is permissioned and user is not whitelisted
+    if deposit_policy == DepositPolicy::WhitelistOnly && !whitelisted {
+        return Err(ContractError::NotWhitelisted);
     }
 
-    // Check if vault cap has been reached and user is not whitelisted to exceed it
+    // Check if vault cap has been reached and user is not allowed to exceed it
     if CAP_REACHED.load(deps.storage)?
-        && !WHITELISTED_DEPOSITORS.has(deps.storage, info.sender.clone())
+        && !(deposit_policy == DepositPolicy::CapBypassOnly && whitelisted)
     {
         return Err(ContractError::CapReached);
     }
@@ -497,6 +1105,40 @@ fn execute_deposit_for_mint(
         &config.min_asset1,
     )?;
 
+    // Check that the deposit fits in the user's allocation when deposits are gated by a merkle root
+    if let Some(root) = DEPOSIT_MERKLE_ROOT.may_load(deps.storage)? {
+        let allocation = allocation.ok_or(ContractError::MissingAllocation)?;
+        verify_allocation(&root, &info.sender, &allocation)?;
+
+        let (price0, price1) = get_asset_prices(&deps.as_ref(), env, PriceUse::Mint)?;
+        let dollars = Uint256::from(mint_assets[0].amount)
+            .checked_mul(price0)?
+            .checked_add(Uint256::from(mint_assets[1].amount).checked_mul(price1)?)?;
+
+        let deposited = DEPOSITED_DOLLARS
+            .may_load(deps.storage, info.sender.clone())?
+            .unwrap_or_default();
+
+        let total_deposited = Uint256::from(deposited).checked_add(dollars)?;
+        if total_deposited > Uint256::from(allocation.max_deposit) {
+            return Err(ContractError::AllocationExceeded {
+                remaining: allocation.max_deposit.saturating_sub(deposited).to_string(),
+            });
+        }
+
+        // within the allocation, so it fits
+        DEPOSITED_DOLLARS.save(
+            deps.storage,
+            info.sender.clone(),
+            &Uint128::try_from(total_deposited)?,
+        )?;
+        PENDING_ALLOCATIONS.update(deps.storage, info.sender.clone(), |pending| {
+            StdResult::Ok(pending.unwrap_or_default() + Uint128::try_from(dollars)?)
+        })?;
+    }
+
+    track_inflow(deps.storage, &mint_assets)?;
+
     // We queue up the assets for the next iteration
     let mut assets_pending = ASSETS_PENDING_MINT.load(deps.storage)?;
 
@@ -525,6 +1167,13 @@ fn execute_deposit_for_mint(
             info.sender.clone(),
             &(mint_assets.clone(), min_out),
         )?;
+
+        // topping up keeps the original place in line
+        let position = NEXT_MINT_QUEUE_POSITION
+            .may_load(deps.storage)?
+            .unwrap_or_default();
+        NEXT_MINT_QUEUE_POSITION.save(deps.storage, &(position + 1))?;
+        MINT_QUEUE_POSITIONS.save(deps.storage, info.sender.clone(), &position)?;
     }
 
     Ok(Response::new().add_attribute("action", "banana_vault_deposit_for_mint"))
@@ -537,10 +1186,8 @@ fn execute_deposit_for_burn(
     address: Option<Addr>,
     amount: Option<Uint128>,
 ) -> Result<Response, ContractError> {
-    // Check if vault is halted
-    if HALTED.load(deps.storage)? {
-        return Err(ContractError::VaultHalted);
-    }
+    // Check if burns are paused
+    assert_not_paused(deps.storage, |p| p.burns, "burns")?;
 
     let mut burn_address = info.sender.clone();
     let mut burn_amount = info.funds[0].amount;
@@ -592,9 +1239,12 @@ fn execute_deposit_for_burn(
 
         ASSETS_PENDING_MINT.save(deps.storage, &assets_pending)?;
         ACCOUNTS_PENDING_MINT.remove(deps.storage, burn_address.clone());
+        MINT_QUEUE_POSITIONS.remove(deps.storage, burn_address.clone());
+        release_allocation(deps.storage, &burn_address, Decimal::one(), Decimal::zero())?;
 
         // Remove empty amounts to avoid sending empty funds in bank msg
         pending_mint.retain(|f| f.amount.ne(&Uint128::zero()));
+        track_outflow(deps.storage, &pending_mint)?;
 
         messages.push(
             BankMsg::Send {
@@ -628,8 +1278,6 @@ fn execute_create_position(
     token_min_amount1: String,
     swap: Option<Swap>,
 ) -> Result<Response, ContractError> {
-    let vault_assets = VAULT_ASSETS.load(deps.storage)?;
-
     if POSITION_OPEN.load(deps.storage)? {
         return Err(ContractError::PositionOpen);
     }
@@ -637,12 +1285,10 @@ fn execute_create_position(
     let mut messages = vec![];
     let mut attributes: Vec<Attribute> = vec![];
 
-    let mut balance_asset0 = deps
-        .querier
-        .query_balance(env.contract.address.clone(), vault_assets.0.denom.clone())?;
-    let mut balance_asset1 = deps
-        .querier
-        .query_balance(env.contract.address.clone(), vault_assets.1.denom.clone())?;
+    let [mut balance_asset0, mut balance_asset1]: [Coin; 2] = TRACKED_BALANCES
+        .load(deps.storage)?
+        .try_into()
+        .map_err(|_| StdError::generic_err("Invalid tracked balances"))?;
 
     // execute swap if provided
     if let Some(swap) = swap {
@@ -709,12 +1355,10 @@ fn execute_add_to_position(
     let mut messages: Vec<CosmosMsg> = vec![];
     let mut attributes: Vec<Attribute> = vec![];
 
-    let mut balance_asset0 = deps
-        .querier
-        .query_balance(contract_address.clone(), vault_assets.0.denom.clone())?;
-    let mut balance_asset1 = deps
-        .querier
-        .query_balance(contract_address.clone(), vault_assets.1.denom.clone())?;
+    let [mut balance_asset0, mut balance_asset1]: [Coin; 2] = TRACKED_BALANCES
+        .load(deps.storage)?
+        .try_into()
+        .map_err(|_| StdError::generic_err("Invalid tracked balances"))?;
 
     // Collect rewards instead of letting them be claimed when adding to position
     let rewards = collect_rewards(
@@ -813,21 +1457,97 @@ fn execute_withdraw_position(
         .add_attributes(attributes))
 }
 
-fn execute_process_mints(deps: DepsMut, env: &Env) -> Result<Response, ContractError> {
-    let (msgs, attrs) = process_mints(deps, env)?;
-    Ok(Response::new().add_messages(msgs).add_attributes(attrs))
+// Test vaults have no pyth contract to push updates to
+fn price_updates_to_push(
+    storage: &dyn Storage,
+    price_updates: Vec<Binary>,
+) -> StdResult<Option<Vec<Binary>>> {
+    let config = CONFIG.load(storage)?;
+    if config.pyth_contract_address == Addr::unchecked(PYTH_DUMMY_CONTRACT_ADDRESS)
+        || price_updates.is_empty()
+    {
+        return Ok(None);
+    }
+    Ok(Some(price_updates))
 }
 
-fn execute_process_burns(deps: DepsMut, env: &Env) -> Result<Response, ContractError> {
-    let (msgs, attrs) = process_burns(deps, env)?;
-    Ok(Response::new().add_messages(msgs).add_attributes(attrs))
-}
+// Nothing would send the funds back when no update fee is paid out of them
+fn assert_no_funds(info: &MessageInfo) -> Result<(), ContractError> {
+    if !info.funds.is_empty() {
+        return Err(ContractError::UnexpectedFunds);
+    }
 
-fn execute_collect_commission(deps: DepsMut) -> Result<Response, ContractError> {
-    let vault_assets = VAULT_ASSETS.load(deps.storage)?;
-    let mut commission_rewards = COMMISSION_REWARDS.load(deps.storage)?;
+    Ok(())
+}
 
-    commission_rewards.retain(|f| !f.amount.is_zero());
+// Forwards the updates to pyth with the fee out of the sent funds, refunding the rest.
+// The reply processes the entries or exits with the fresh prices
+fn execute_push_price_updates(
+    deps: DepsMut,
+    info: &MessageInfo,
+    price_updates: Vec<Binary>,
+    reply_id: u64,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let fee = get_update_fee(
+        &deps.querier,
+        config.pyth_contract_address.clone(),
+        &price_updates,
+    )?;
+
+    let mut funds = Coins::try_from(info.funds.clone()).unwrap_or_default();
+    if !fee.amount.is_zero() {
+        funds
+            .sub(fee.clone())
+            .map_err(|_| ContractError::InsufficientUpdateFee {
+                fee: fee.to_string(),
+            })?;
+    }
+
+    let update_msg = WasmMsg::Execute {
+        contract_addr: config.pyth_contract_address.to_string(),
+        msg: to_json_binary(&PythExecuteMsg::UpdatePriceFeeds {
+            data: price_updates,
+        })?,
+        funds: if fee.amount.is_zero() {
+            vec![]
+        } else {
+            vec![fee.clone()]
+        },
+    };
+
+    let mut response = Response::new()
+        .add_submessage(SubMsg::reply_on_success(update_msg, reply_id))
+        .add_attribute("action", "banana_vault_push_price_updates")
+        .add_attribute("fee", fee.to_string());
+
+    if !funds.is_empty() {
+        response = response.add_message(BankMsg::Send {
+            to_address: info.sender.to_string(),
+            amount: funds.into_vec(),
+        });
+    }
+
+    Ok(response)
+}
+
+fn execute_process_mints(deps: DepsMut, env: &Env) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, |p| p.mints, "mints")?;
+    let (msgs, attrs) = process_mints(deps, env)?;
+    Ok(Response::new().add_messages(msgs).add_attributes(attrs))
+}
+
+fn execute_process_burns(deps: DepsMut, env: &Env) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, |p| p.burns, "burns")?;
+    let (msgs, attrs) = process_burns(deps, env)?;
+    Ok(Response::new().add_messages(msgs).add_attributes(attrs))
+}
+
+fn execute_collect_commission(deps: DepsMut) -> Result<Response, ContractError> {
+    let vault_assets = VAULT_ASSETS.load(deps.storage)?;
+    let mut commission_rewards = COMMISSION_REWARDS.load(deps.storage)?;
+
+    commission_rewards.retain(|f| !f.amount.is_zero());
 
     if commission_rewards.is_empty() {
         return Err(ContractError::CannotClaim);
@@ -837,36 +1557,118 @@ fn execute_collect_commission(deps: DepsMut) -> Result<Response, ContractError>
         deps.storage,
         &vec![coin(0, vault_assets.0.denom), coin(0, vault_assets.1.denom)],
     )?;
+    track_outflow(deps.storage, &commission_rewards)?;
 
     Ok(Response::new()
-        .add_message(BankMsg::Send {
-            to_address: CONFIG.load(deps.storage)?.commission_receiver.to_string(),
-            amount: commission_rewards,
-        })
+        .add_messages(prepare_commission_payouts(
+            deps.storage,
+            commission_rewards,
+        )?)
         .add_attribute("action", "banana_vault_claim_commission"))
 }
 
-fn execute_halt(deps: DepsMut) -> Result<Response, ContractError> {
-    HALTED.save(deps.storage, &true)?;
-    Ok(Response::new().add_attribute("action", "banana_vault_halt"))
+fn execute_accrue_fees(deps: DepsMut, env: &Env) -> Result<Response, ContractError> {
+    let (msgs, attrs) = accrue_management_fee(deps.storage, env)?;
+    Ok(Response::new().add_messages(msgs).add_attributes(attrs))
+}
+
+fn execute_heartbeat(deps: DepsMut, env: &Env) -> Result<Response, ContractError> {
+    LAST_UPDATE.save(deps.storage, &env.block.time.seconds())?;
+    Ok(Response::new().add_attribute("action", "banana_vault_heartbeat"))
+}
+
+fn execute_absorb_donations(deps: DepsMut, env: &Env) -> Result<Response, ContractError> {
+    let mut donations = get_untracked_balances(&deps.as_ref(), env)?;
+    track_inflow(deps.storage, &donations)?;
+
+    donations.retain(|c| !c.amount.is_zero());
+
+    let mut attributes = vec![attr("action", "banana_vault_absorb_donations")];
+    for donation in donations {
+        attributes.push(attr("absorbed", donation.to_string()));
+    }
+
+    Ok(Response::new().add_attributes(attributes))
+}
+
+// Test vaults start with the mock oracle quoting the seed prices
+pub(crate) fn seed_mock_prices(storage: &mut dyn Storage) -> StdResult<()> {
+    for (identifier, price) in MOCK_PRICE_SEEDS {
+        MOCK_PRICES.save(
+            storage,
+            identifier.to_string(),
+            &MockPrice {
+                price_identifier: PriceIdentifier::from_hex(identifier)
+                    .map_err(|e| StdError::generic_err(e.to_string()))?,
+                price: Uint128::new(price),
+                conf: Uint128::zero(),
+                expo: -8,
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+fn execute_set_mock_prices(
+    deps: DepsMut,
+    prices: Vec<MockPrice>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.pyth_contract_address != Addr::unchecked(PYTH_DUMMY_CONTRACT_ADDRESS) {
+        return Err(ContractError::MockOracleDisabled);
+    }
+
+    let mut attributes = vec![attr("action", "banana_vault_set_mock_prices")];
+    for price in prices {
+        attributes.push(attr("price_identifier", price.price_identifier.to_hex()));
+        MOCK_PRICES.save(deps.storage, price.price_identifier.to_hex(), &price)?;
+    }
+
+    Ok(Response::new().add_attributes(attributes))
+}
+
+// Only the selected switches are turned on, the rest are left as they are
+fn execute_pause(deps: DepsMut, pauses: &Pauses) -> Result<Response, ContractError> {
+    let mut paused = PAUSED.load(deps.storage)?;
+    paused.mints |= pauses.mints;
+    paused.burns |= pauses.burns;
+    paused.positions |= pauses.positions;
+    paused.compounding |= pauses.compounding;
+    PAUSED.save(deps.storage, &paused)?;
+
+    Ok(Response::new().add_attribute("action", "banana_vault_pause"))
 }
 
-fn execute_resume(deps: DepsMut) -> Result<Response, ContractError> {
-    HALTED.save(deps.storage, &false)?;
+// Only the selected switches are turned off, the rest are left as they are. Resuming mints or burns
+// also forgets the last processed price, so the circuit breaker accepts the current one
+fn execute_resume(deps: DepsMut, pauses: &Pauses) -> Result<Response, ContractError> {
+    if pauses.mints || pauses.burns {
+        LAST_VAULT_PRICE.remove(deps.storage);
+    }
+
+    let mut paused = PAUSED.load(deps.storage)?;
+    paused.mints &= !pauses.mints;
+    paused.burns &= !pauses.burns;
+    paused.positions &= !pauses.positions;
+    paused.compounding &= !pauses.compounding;
+    PAUSED.save(deps.storage, &paused)?;
+
     Ok(Response::new().add_attribute("action", "banana_vault_resume"))
 }
 
-fn execute_unlock(deps: DepsMut, env: &Env, info: &MessageInfo) -> Result<Response, ContractError> {
+fn execute_unlock(
+    mut deps: DepsMut,
+    env: &Env,
+    info: &MessageInfo,
+) -> Result<Response, ContractError> {
     // Only the operator can unlock the vault, unless the vault has not been updated for a long time
-    if info.sender != OPERATOR.load(deps.storage)?
-        && env.block.time.seconds() < LAST_UPDATE.load(deps.storage)? + MAX_UPDATE_INTERVAL
-    {
-        return Err(ContractError::CantUnlockYet {
-            seconds: LAST_UPDATE.load(deps.storage)? + MAX_UPDATE_INTERVAL
-                - env.block.time.seconds(),
-        });
+    let unlock_in = seconds_until_unlock(deps.storage, env)?;
+    if info.sender != OPERATOR.load(deps.storage)? && unlock_in > 0 {
+        return Err(ContractError::CantUnlockYet { seconds: unlock_in });
     }
 
+    let untracked = get_untracked_balances(&deps.as_ref(), env)?;
     let mut messages = vec![];
     let mut attributes = vec![];
 
@@ -877,6 +1679,8 @@ fn execute_unlock(deps: DepsMut, env: &Env, info: &MessageInfo) -> Result<Respon
         .collect();
 
     for (address, (amount, _)) in &addresses_pending_activation {
+        release_allocation(deps.storage, address, Decimal::one(), Decimal::zero())?;
+
         let mut funds = amount.clone();
 
         // Remove empty amounts to avoid sending empty funds in bank msg
@@ -891,6 +1695,7 @@ fn execute_unlock(deps: DepsMut, env: &Env, info: &MessageInfo) -> Result<Respon
     }
 
     ACCOUNTS_PENDING_MINT.clear(deps.storage);
+    MINT_QUEUE_POSITIONS.clear(deps.storage);
 
     // if an open position exists, close it
     if POSITION_OPEN.load(deps.storage)? {
@@ -928,19 +1733,54 @@ fn execute_unlock(deps: DepsMut, env: &Env, info: &MessageInfo) -> Result<Respon
         POSITION_OPEN.save(deps.storage, &false)?;
     }
 
-    // set terminated to true and halted to false to allow immediate redemptions
-    HALTED.save(deps.storage, &false)?;
+    // set terminated to true and lift all pauses to allow immediate redemptions
+    PAUSED.save(deps.storage, &Pauses::default())?;
     TERMINATED.save(deps.storage, &true)?;
     attributes.push(attr("action", "banana_vault_terminate"));
 
     // process any pending burns
-    let (burn_msgs, burn_attrs) = process_burns(deps, env)?;
+    let (burn_msgs, burn_attrs) = process_burns(deps.branch(), env)?;
     messages.extend(burn_msgs);
     attributes.extend(burn_attrs);
 
-    Ok(Response::new()
-        .add_messages(messages)
-        .add_attributes(attributes))
+    // the refunds and the withdrawn position are accounted for once everything has gone through
+    Ok(sync_balances_after(
+        deps.storage,
+        untracked,
+        Response::new()
+            .add_messages(messages)
+            .add_attributes(attributes),
+    )?)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    match msg.id {
+        SYNC_BALANCES_REPLY_ID => {
+            // whatever was untracked before the messages stays untracked, the rest is the vault's
+            let untracked = BALANCES_SNAPSHOT.load(deps.storage)?;
+            let tracked = untracked
+                .into_iter()
+                .map(|untracked| {
+                    let balance = deps
+                        .querier
+                        .query_balance(&env.contract.address, &untracked.denom)?;
+                    Ok(coin(
+                        balance.amount.saturating_sub(untracked.amount).u128(),
+                        untracked.denom,
+                    ))
+                })
+                .collect::<StdResult<Vec<Coin>>>()?;
+
+            TRACKED_BALANCES.save(deps.storage, &tracked)?;
+            BALANCES_SNAPSHOT.remove(deps.storage);
+
+            Ok(Response::new().add_attribute("action", "banana_vault_sync_balances"))
+        }
+        PROCESS_MINTS_REPLY_ID => execute_process_mints(deps, &env),
+        PROCESS_BURNS_REPLY_ID => execute_process_burns(deps, &env),
+        id => Err(ContractError::UnknownReplyId { id }),
+    }
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
@@ -969,13 +1809,27 @@ pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
             RewardQuery::Commission => to_json_binary(&query_commission_rewards(deps)?),
             RewardQuery::Uncompounded => to_json_binary(&query_uncompounded_rewards(deps)?),
         },
+        QueryMsg::Donations => to_json_binary(&get_untracked_balances(&deps, &env)?),
+        QueryMsg::AddressCap { address } => {
+            to_json_binary(&get_address_cap(deps.storage, &address)?)
+        }
+        QueryMsg::DepositedDollars { address } => {
+            to_json_binary(&query_deposited_dollars(deps, address)?)
+        }
         QueryMsg::Whitelist { start_after, limit } => {
             to_json_binary(&query_whitelist(deps, start_after, limit))
         }
         QueryMsg::VaultState(state_query) => match state_query {
             StateQuery::Info => to_json_binary(&query_info(deps)?),
             StateQuery::Status => to_json_binary(&query_status(deps, &env)?),
+            StateQuery::Nav => to_json_binary(&query_nav(deps, &env)?),
         },
+        QueryMsg::Roles { start_after, limit } => {
+            to_json_binary(&query_roles(deps, start_after, limit))
+        }
+        QueryMsg::QueuedChanges { start_after, limit } => {
+            to_json_binary(&query_queued_changes(deps, start_after, limit))
+        }
     }
 }
 
@@ -1002,30 +1856,49 @@ fn query_estimate_mint(deps: Deps, env: &Env, coins: &[Coin]) -> StdResult<Vec<C
 
     let (asset0, asset1) = get_vault_balances(&deps, &env.contract.address.to_string(), true)?;
 
-    let pricing = get_vault_pricing(&deps, env, &asset0.amount, &asset1.amount)?;
-
-    let dollars_asset0 = mint_funds[0].amount.checked_mul(pricing.price0)?;
-    let dollars_asset1 = mint_funds[1].amount.checked_mul(pricing.price1)?;
-
-    let total_dollars_to_mint = dollars_asset0.checked_add(dollars_asset1)?;
+    let pricing = get_vault_pricing(&deps, env, &asset0.amount, &asset1.amount, PriceUse::Mint)?;
+
+    let deposit_fee = Decimal::one() - config.deposit_fee.unwrap_or_default();
+    let total_dollars_to_mint = get_dollars(
+        &[
+            coin(
+                mint_funds[0].amount.mul_ceil(deposit_fee).u128(),
+                &mint_funds[0].denom,
+            ),
+            coin(
+                mint_funds[1].amount.mul_ceil(deposit_fee).u128(),
+                &mint_funds[1].denom,
+            ),
+        ],
+        &pricing,
+    )?;
 
     Ok(vec![coin(
-        total_dollars_to_mint
-            .checked_div(pricing.vault_price)?
-            .u128(),
+        pricing.to_shares(total_dollars_to_mint)?.u128(),
         VAULT_DENOM.load(deps.storage)?,
     )])
 }
 
 fn query_estimate_burn(deps: Deps, env: &Env, amount: Uint128) -> StdResult<Vec<Coin>> {
     let (asset0, asset1) = get_vault_balances(&deps, &env.contract.address.to_string(), true)?;
-    let ratio = Decimal::new(amount)
-        .checked_div(Decimal::new(SUPPLY.load(deps.storage)?))
-        .unwrap();
+    let supply = SUPPLY
+        .load(deps.storage)?
+        .checked_add(Uint128::new(VIRTUAL_SHARES))?;
+    let withdraw_fee = get_withdraw_fee(deps.storage)?;
+
+    // rounded down in favour of the vault
+    let amount0 = asset0.amount.multiply_ratio(amount, supply);
+    let amount1 = asset1.amount.multiply_ratio(amount, supply);
 
     Ok(vec![
-        coin(asset0.amount.mul_floor(ratio).u128(), asset0.denom),
-        coin(asset1.amount.mul_floor(ratio).u128(), asset1.denom),
+        coin(
+            (amount0 - amount0.mul_floor(withdraw_fee)).u128(),
+            asset0.denom,
+        ),
+        coin(
+            (amount1 - amount1.mul_floor(withdraw_fee)).u128(),
+            asset1.denom,
+        ),
     ])
 }
 
@@ -1108,6 +1981,12 @@ fn query_uncompounded_rewards(deps: Deps) -> StdResult<Vec<Coin>> {
     UNCOMPOUNDED_REWARDS.load(deps.storage)
 }
 
+fn query_deposited_dollars(deps: Deps, address: Addr) -> StdResult<Uint128> {
+    Ok(DEPOSITED_DOLLARS
+        .may_load(deps.storage, address)?
+        .unwrap_or_default())
+}
+
 fn query_whitelist(deps: Deps, start_after: Option<Addr>, limit: Option<u32>) -> WhitelistResponse {
     let limit = limit.unwrap_or(MAX_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
     let start = start_after.map(Bound::exclusive);
@@ -1123,6 +2002,39 @@ fn query_whitelist(deps: Deps, start_after: Option<Addr>, limit: Option<u32>) ->
     }
 }
 
+fn query_roles(deps: Deps, start_after: Option<Addr>, limit: Option<u32>) -> RolesResponse {
+    let limit = limit.unwrap_or(MAX_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    let start = start_after.map(Bound::exclusive);
+    let roles: Vec<RoleAssignment> = ROLES
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit as usize)
+        .filter_map(Result::ok)
+        .map(|(address, roles)| RoleAssignment { address, roles })
+        .collect();
+
+    RolesResponse { roles }
+}
+
+fn query_queued_changes(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> Vec<QueuedChangeResponse> {
+    let limit = limit.unwrap_or(MAX_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    let start = start_after.map(Bound::exclusive);
+
+    TIMELOCK_QUEUE
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit as usize)
+        .filter_map(Result::ok)
+        .map(|(id, queued)| QueuedChangeResponse {
+            id,
+            change: queued.change,
+            eta: queued.eta,
+        })
+        .collect()
+}
+
 fn query_info(deps: Deps) -> StdResult<State> {
     let vault_assets = VAULT_ASSETS.load(deps.storage)?;
     let commission_rate = COMMISSION_RATE.load(deps.storage)?;
@@ -1131,13 +2043,35 @@ fn query_info(deps: Deps) -> StdResult<State> {
         asset0: vault_assets.0,
         asset1: vault_assets.1,
         pool_id: POOL_ID.load(deps.storage)?,
-        owner: OWNER.load(deps.storage)?,
+        owner: OWNER.may_load(deps.storage)?,
+        pending_owner: PENDING_OWNER.may_load(deps.storage)?,
         operator: OPERATOR.load(deps.storage)?,
         commission_rate,
+        max_commission: MAX_COMMISSION.load(deps.storage)?,
+        pending_commission: PENDING_COMMISSION.may_load(deps.storage)?,
+        commission_receivers: COMMISSION_RECEIVERS.load(deps.storage)?,
         config: Box::new(CONFIG.load(deps.storage)?),
     })
 }
 
+fn query_nav(deps: Deps, env: &Env) -> StdResult<State> {
+    let (asset0, asset1) = get_vault_balances(&deps, &env.contract.address.to_string(), true)?;
+    let pricing = get_vault_pricing(
+        &deps,
+        env,
+        &asset0.amount,
+        &asset1.amount,
+        PriceUse::Valuation,
+    )?;
+
+    Ok(State::Nav {
+        numeraire: CONFIG.load(deps.storage)?.numeraire,
+        total_value: pricing.total_dollars,
+        vault_price: pricing.vault_price(),
+        supply: SUPPLY.load(deps.storage)?,
+    })
+}
+
 fn query_status(deps: Deps, env: &Env) -> StdResult<State> {
     let mut join_time = 0;
     let mut uptime_locked = false;
@@ -1170,8 +2104,9 @@ fn query_status(deps: Deps, env: &Env) -> StdResult<State> {
         join_time,
         uptime_locked,
         last_update: LAST_UPDATE.load(deps.storage)?,
+        unlock_in: seconds_until_unlock(deps.storage, env)?,
         cap_reached: CAP_REACHED.load(deps.storage)?,
-        halted: HALTED.load(deps.storage)?,
+        paused: PAUSED.load(deps.storage)?,
         terminated: TERMINATED.load(deps.storage)?,
         supply: SUPPLY.load(deps.storage)?,
         denom: VAULT_DENOM.load(deps.storage)?,
@@ -1179,22 +2114,150 @@ fn query_status(deps: Deps, env: &Env) -> StdResult<State> {
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> StdResult<Response> {
+pub fn migrate(mut deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
     let version = get_contract_version(deps.storage)?;
     if version.contract != CONTRACT_NAME {
-        return Err(StdError::generic_err("Can only upgrade from same type"));
+        return Err(StdError::generic_err("Can only upgrade from same type").into());
     };
     if INCOMPATIBLE_TAGS.contains(&version.version.as_str()) {
-        return Err(StdError::generic_err(
-            "Cannot upgrade from incompatible version",
-        ));
+        return Err(StdError::generic_err("Cannot upgrade from incompatible version").into());
     }
+
+    let attributes = run_migrations(
+        deps.branch(),
+        &env,
+        &version.version,
+        CONTRACT_VERSION,
+        &msg,
+    )?;
+
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
-    Ok(Response::default())
+    Ok(Response::new()
+        .add_attribute("action", "banana_vault_migrate")
+        .add_attribute("from_version", version.version)
+        .add_attribute("to_version", CONTRACT_VERSION)
+        .add_attributes(attributes))
 }
 
 // Helpers
+
+// Publishes the vault token metadata to the bank module, so wallets can display it
+fn set_denom_metadata(env: &Env, vault_denom: &str, metadata: &Option<Metadata>) -> CosmosMsg {
+    let subdenom = vault_denom
+        .trim_start_matches(&format!("factory/{}/", env.contract.address))
+        .to_string();
+    let metadata = metadata.clone().unwrap_or(Metadata {
+        name: subdenom.clone(),
+        description: None,
+        image: None,
+        symbol: None,
+        exponent: None,
+    });
+    let symbol = metadata.symbol.unwrap_or(subdenom);
+
+    MsgSetDenomMetadata {
+        sender: env.contract.address.to_string(),
+        metadata: Some(BankMetadata {
+            description: metadata.description.unwrap_or_default(),
+            denom_units: vec![
+                DenomUnit {
+                    denom: vault_denom.to_string(),
+                    exponent: 0,
+                    aliases: vec![],
+                },
+                DenomUnit {
+                    denom: symbol.clone(),
+                    exponent: metadata.exponent.unwrap_or(DEFAULT_EXPONENT),
+                    aliases: vec![],
+                },
+            ],
+            base: vault_denom.to_string(),
+            display: symbol.clone(),
+            name: metadata.name,
+            symbol,
+            uri: metadata.image.unwrap_or_default(),
+            uri_hash: String::new(),
+        }),
+    }
+    .into()
+}
+
+fn assert_owner(storage: &dyn Storage, sender: &Addr) -> Result<(), ContractError> {
+    // the owner may have been renounced
+    if OWNER.may_load(storage)?.as_ref() != Some(sender) {
+        return Err(ContractError::Unauthorized);
+    }
+
+    Ok(())
+}
+
+// Time left before the dead man switch lets anyone unlock the vault
+fn seconds_until_unlock(storage: &dyn Storage, env: &Env) -> StdResult<u64> {
+    let unlock_time = LAST_UPDATE.load(storage)? + CONFIG.load(storage)?.update_interval;
+    Ok(unlock_time.saturating_sub(env.block.time.seconds()))
+}
+
+// Only the operator keeps the dead man switch from firing, other roles may outlive it
+fn refresh_last_update(storage: &mut dyn Storage, env: &Env, sender: &Addr) -> StdResult<()> {
+    if *sender == OPERATOR.load(storage)? {
+        LAST_UPDATE.save(storage, &env.block.time.seconds())?;
+    }
+
+    Ok(())
+}
+
+// The cap set for the address, or the default one
+fn get_address_cap(storage: &dyn Storage, address: &Addr) -> StdResult<Option<Uint128>> {
+    match ADDRESS_CAPS.may_load(storage, address.clone())? {
+        Some(cap) => Ok(Some(cap)),
+        None => Ok(CONFIG.load(storage)?.address_cap),
+    }
+}
+
+fn assert_not_paused(
+    storage: &dyn Storage,
+    switch: impl Fn(&Pauses) -> bool,
+    action: &str,
+) -> Result<(), ContractError> {
+    if switch(&PAUSED.load(storage)?) {
+        return Err(ContractError::Paused {
+            action: action.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+// The operator holds every role, and the owner every role except managing the position
+fn assert_role(storage: &dyn Storage, sender: &Addr, role: Role) -> Result<(), ContractError> {
+    if *sender == OPERATOR.load(storage)?
+        || (role != Role::Strategist && OWNER.may_load(storage)?.as_ref() == Some(sender))
+        || ROLES
+            .may_load(storage, sender.clone())?
+            .is_some_and(|roles| roles.contains(&role))
+    {
+        return Ok(());
+    }
+
+    Err(ContractError::MissingRole {
+        role: format!("{role:?}"),
+    })
+}
+
+// The oracle must be a deployed contract, so a live vault can't be moved onto the mock oracle
+fn verify_oracle_address(deps: &Deps, address: &Addr) -> Result<(), ContractError> {
+    deps.api.addr_validate(address.as_str())?;
+
+    if deps.querier.query_wasm_contract_info(address).is_err() {
+        return Err(ContractError::InvalidOracleAddress {
+            address: address.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
 fn verify_pool(
     deps: &Deps,
     pool_id: u64,
@@ -1227,6 +2290,130 @@ fn verify_pool(
     Ok(())
 }
 
+pub(crate) fn verify_config(config: &Config) -> Result<(), ContractError> {
+    if !(MIN_UPDATE_INTERVAL..=MAX_UPDATE_INTERVAL).contains(&config.update_interval) {
+        return Err(ContractError::InvalidUpdateInterval {
+            min: MIN_UPDATE_INTERVAL,
+            max: MAX_UPDATE_INTERVAL,
+        });
+    }
+    if config.timelock_delay < MIN_TIMELOCK_DELAY {
+        return Err(ContractError::TimelockDelayTooShort {
+            min: MIN_TIMELOCK_DELAY,
+        });
+    }
+    if config.commission_delay < MIN_COMMISSION_DELAY {
+        return Err(ContractError::CommissionDelayTooShort {
+            min: MIN_COMMISSION_DELAY,
+        });
+    }
+    if config.management_fee.unwrap_or_default() >= Decimal::percent(100) {
+        return Err(ContractError::ManagementFeeTooHigh);
+    }
+    if config.deposit_fee.unwrap_or_default() >= Decimal::percent(100) {
+        return Err(ContractError::FeeTooHigh {
+            fee: "Deposit".to_string(),
+        });
+    }
+    if config.withdraw_fee.unwrap_or_default() >= Decimal::percent(100) {
+        return Err(ContractError::FeeTooHigh {
+            fee: "Withdraw".to_string(),
+        });
+    }
+    if let Some(metadata) = &config.metadata {
+        if metadata.name.trim().is_empty() {
+            return Err(ContractError::InvalidDenomMetadata {
+                reason: "name can't be empty".to_string(),
+            });
+        }
+        // The bank module rejects symbols that are not valid denoms and a display unit equal to the base
+        if metadata.symbol.as_ref().is_some_and(|s| !is_valid_denom(s)) {
+            return Err(ContractError::InvalidDenomMetadata {
+                reason: "symbol must be a valid denom".to_string(),
+            });
+        }
+        if metadata.exponent == Some(0) {
+            return Err(ContractError::InvalidDenomMetadata {
+                reason: "exponent must be greater than zero".to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Same rules as the cosmos sdk: [a-zA-Z][a-zA-Z0-9/:._-]{2,127}
+fn is_valid_denom(denom: &str) -> bool {
+    let mut chars = denom.chars();
+    (3..=128).contains(&denom.len())
+        && chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "/:._-".contains(c))
+}
+
+// Checks that sha256(0x00 + address length as u32 BE + address + max_deposit as u128 BE) is a leaf
+// of the tree. Inner nodes are sha256(0x01 + pair), with the pair in sorted order
+fn verify_allocation(
+    root: &str,
+    address: &Addr,
+    allocation: &Allocation,
+) -> Result<(), ContractError> {
+    let mut hash: [u8; 32] = Sha256::new()
+        .chain_update([MERKLE_LEAF_PREFIX])
+        .chain_update((address.as_str().len() as u32).to_be_bytes())
+        .chain_update(address.as_bytes())
+        .chain_update(allocation.max_deposit.u128().to_be_bytes())
+        .finalize()
+        .into();
+
+    for sibling in &allocation.proof {
+        let mut sibling_buf: [u8; 32] = [0; 32];
+        hex::decode_to_slice(sibling, &mut sibling_buf)
+            .map_err(|_| ContractError::InvalidMerkleProof)?;
+
+        let mut pair = [hash, sibling_buf];
+        pair.sort_unstable();
+        hash = Sha256::new()
+            .chain_update([MERKLE_NODE_PREFIX])
+            .chain_update(pair.concat())
+            .finalize()
+            .into();
+    }
+
+    let mut root_buf: [u8; 32] = [0; 32];
+    hex::decode_to_slice(root, &mut root_buf).map_err(|_| ContractError::InvalidMerkleProof)?;
+
+    if hash != root_buf {
+        return Err(ContractError::InvalidMerkleProof);
+    }
+
+    Ok(())
+}
+
+fn verify_commission_receivers(
+    deps: &Deps,
+    receivers: &[CommissionReceiver],
+) -> Result<(), ContractError> {
+    let mut total_weight = Decimal::zero();
+
+    for (i, receiver) in receivers.iter().enumerate() {
+        deps.api.addr_validate(receiver.address.as_str())?;
+        if receiver.weight.is_zero()
+            || receivers[..i]
+                .iter()
+                .any(|other| other.address == receiver.address)
+        {
+            return Err(ContractError::InvalidCommissionReceivers);
+        }
+        total_weight = total_weight.checked_add(receiver.weight)?;
+    }
+
+    if total_weight != Decimal::one() {
+        return Err(ContractError::InvalidCommissionReceivers);
+    }
+
+    Ok(())
+}
+
 fn verify_mint_funds(
     funds: &[Coin],
     denom0: String,
@@ -1350,12 +2537,12 @@ fn get_vault_balances(
     include_position: bool,
 ) -> Result<(Coin, Coin), StdError> {
     let vault_assets = VAULT_ASSETS.load(deps.storage)?;
-    let mut asset0 = deps
-        .querier
-        .query_balance(address.clone(), vault_assets.0.denom.clone())?;
-    let mut asset1 = deps
-        .querier
-        .query_balance(address, vault_assets.1.denom.clone())?;
+
+    // only the tracked balances count, so sending funds to the contract doesn't move the vault price
+    let [mut asset0, mut asset1]: [Coin; 2] = TRACKED_BALANCES
+        .load(deps.storage)?
+        .try_into()
+        .map_err(|_| StdError::generic_err("Invalid tracked balances"))?;
 
     if POSITION_OPEN.load(deps.storage)? && include_position {
         let commission_remainder = Decimal::one() - COMMISSION_RATE.load(deps.storage)?;
@@ -1429,6 +2616,59 @@ fn get_vault_balances(
     ))
 }
 
+// Vault assets held by the contract above the tracked balances, i.e. sent to it directly
+fn get_untracked_balances(deps: &Deps, env: &Env) -> StdResult<Vec<Coin>> {
+    TRACKED_BALANCES
+        .load(deps.storage)?
+        .into_iter()
+        .map(|tracked| {
+            let balance = deps
+                .querier
+                .query_balance(&env.contract.address, &tracked.denom)?;
+            Ok(coin(
+                balance.amount.saturating_sub(tracked.amount).u128(),
+                tracked.denom,
+            ))
+        })
+        .collect()
+}
+
+fn track_inflow(storage: &mut dyn Storage, coins: &[Coin]) -> StdResult<()> {
+    let mut tracked = TRACKED_BALANCES.load(storage)?;
+    for coin in coins {
+        if let Some(balance) = tracked.iter_mut().find(|b| b.denom == coin.denom) {
+            balance.amount = balance.amount.checked_add(coin.amount)?;
+        }
+    }
+    TRACKED_BALANCES.save(storage, &tracked)
+}
+
+fn track_outflow(storage: &mut dyn Storage, coins: &[Coin]) -> StdResult<()> {
+    let mut tracked = TRACKED_BALANCES.load(storage)?;
+    for coin in coins {
+        if let Some(balance) = tracked.iter_mut().find(|b| b.denom == coin.denom) {
+            balance.amount = balance.amount.checked_sub(coin.amount)?;
+        }
+    }
+    TRACKED_BALANCES.save(storage, &tracked)
+}
+
+// Swaps, positions and reward collection move amounts only known once they are executed, so the
+// tracked balances are resynced in the reply to the last message. The untracked balances taken
+// before executing are kept out
+fn sync_balances_after(
+    storage: &mut dyn Storage,
+    untracked: Vec<Coin>,
+    mut response: Response,
+) -> StdResult<Response> {
+    if let Some(last) = response.messages.last_mut() {
+        last.id = SYNC_BALANCES_REPLY_ID;
+        last.reply_on = ReplyOn::Success;
+        BALANCES_SNAPSHOT.save(storage, &untracked)?;
+    }
+    Ok(response)
+}
+
 struct Rewards {
     amount0: Uint128,
     amount1: Uint128,
@@ -1636,17 +2876,86 @@ fn prepare_force_burn(
     Ok(vec![msg_burn.into(), msg_mint.into()])
 }
 
-fn process_mints(
-    deps: DepsMut,
-    env: &Env,
-) -> Result<(Vec<CosmosMsg>, Vec<Attribute>), ContractError> {
-    let entries: Vec<(Addr, (Vec<Coin>, Uint128))> = ACCOUNTS_PENDING_MINT
-        .range(deps.storage, None, None, Order::Ascending)
-        .filter_map(Result::ok)
-        .collect();
+// A pending mint once the part of it over the address cap has been set aside
+struct MintRequest {
+    address: Addr,
+    queue_position: u64,
+    // everything the account has pending
+    deposited: Vec<Coin>,
+    min_out: Uint128,
+    // what is left to mint after the address cap, and its dollar value
+    coins: Vec<Coin>,
+    dollars: Uint256,
+    refund: Vec<Coin>,
+    // whitelisted depositors may go over the dollar cap
+    bypass_cap: bool,
+}
 
-    if entries.is_empty() {
-        return Ok((vec![], vec![]));
+fn get_dollars(coins: &[Coin], pricing: &Pricing) -> StdResult<Uint256> {
+    Ok(Uint256::from(coins[0].amount)
+        .checked_mul(pricing.price0)?
+        .checked_add(Uint256::from(coins[1].amount).checked_mul(pricing.price1)?)?)
+}
+
+// Gives the refunded share of a pending deposit back to the account's allocation, the queued share
+// stays pending and the rest is minted so it keeps counting
+fn release_allocation(
+    storage: &mut dyn Storage,
+    address: &Addr,
+    refunded: Decimal,
+    queued: Decimal,
+) -> StdResult<()> {
+    let Some(pending) = PENDING_ALLOCATIONS.may_load(storage, address.clone())? else {
+        return Ok(());
+    };
+
+    let released = pending.mul_floor(refunded);
+    if !released.is_zero() {
+        let deposited = DEPOSITED_DOLLARS.load(storage, address.clone())?;
+        DEPOSITED_DOLLARS.save(
+            storage,
+            address.clone(),
+            &deposited.saturating_sub(released),
+        )?;
+    }
+
+    let still_pending = pending.mul_floor(queued);
+    if still_pending.is_zero() {
+        PENDING_ALLOCATIONS.remove(storage, address.clone());
+    } else {
+        PENDING_ALLOCATIONS.save(storage, address.clone(), &still_pending)?;
+    }
+
+    Ok(())
+}
+
+// Share of the requested dollars that fits in the headroom, rounded down
+fn fill_ratio(headroom: Uint256, requested: Uint256) -> Decimal {
+    if headroom >= requested {
+        return Decimal::one();
+    }
+
+    // below one, so it always fits in a Decimal
+    Decimal256::checked_from_ratio(headroom, requested)
+        .ok()
+        .and_then(|ratio| Decimal::try_from(ratio).ok())
+        .unwrap_or_default()
+}
+
+fn process_mints(
+    mut deps: DepsMut,
+    env: &Env,
+) -> Result<(Vec<CosmosMsg>, Vec<Attribute>), ContractError> {
+    // fees are settled first so that the new shares are priced on the diluted supply
+    let (mut messages, mut attributes) = accrue_management_fee(deps.storage, env)?;
+
+    let entries: Vec<(Addr, (Vec<Coin>, Uint128))> = ACCOUNTS_PENDING_MINT
+        .range(deps.storage, None, None, Order::Ascending)
+        .filter_map(Result::ok)
+        .collect();
+
+    if entries.is_empty() {
+        return Ok((messages, attributes));
     }
 
     let config = CONFIG.load(deps.storage)?;
@@ -1654,45 +2963,228 @@ fn process_mints(
     let (asset0, asset1) =
         get_vault_balances(&deps.as_ref(), &env.contract.address.to_string(), true)?;
 
-    let pricing = get_vault_pricing(&deps.as_ref(), env, &asset0.amount, &asset1.amount)?;
+    let pricing = get_vault_pricing(
+        &deps.as_ref(),
+        env,
+        &asset0.amount,
+        &asset1.amount,
+        PriceUse::Mint,
+    )?;
+
+    if let Some(breaker_attrs) =
+        check_circuit_breaker(deps.branch(), env, &asset0.amount, &asset1.amount)?
+    {
+        attributes.extend(breaker_attrs);
+        return Ok((messages, attributes));
+    }
 
     let mut total_dollars_in_vault = pricing.total_dollars;
 
-    let mut messages: Vec<CosmosMsg> = vec![];
-    let mut attributes = vec![attr("action", "banana_vault_mint")];
+    attributes.push(attr("action", "banana_vault_mint"));
 
     let mut total_minted = Uint128::zero();
     let mut pending_assets = ASSETS_PENDING_MINT.load(deps.storage)?;
+    let mut commission_rewards = COMMISSION_REWARDS.load(deps.storage)?;
+
+    let deposit_fee = config.deposit_fee.unwrap_or_default();
+    let fees_to_commission = config.fee_destination == Some(FeeDestination::Commission);
+
+    let vault_denom = VAULT_DENOM.load(deps.storage)?;
+
+    // first work out what each account asks for, the part of a deposit over its address cap is refunded
+    let mut requests = vec![];
+    for (address, (deposited, mut min_out)) in entries {
+        let mut coins = deposited.clone();
+        let mut refund = vec![coin(0, &deposited[0].denom), coin(0, &deposited[1].denom)];
+
+        if let Some(address_cap) = get_address_cap(deps.storage, &address)? {
+            let held_dollars =
+                pricing.to_dollars(deps.querier.query_balance(&address, &vault_denom)?.amount)?;
+            let room = Uint256::from(address_cap).saturating_sub(held_dollars);
+
+            let deposit_dollars = get_dollars(&coins, &pricing)?;
+            if deposit_dollars > room {
+                let accepted = fill_ratio(room, deposit_dollars);
+                for (coin, refund) in coins.iter_mut().zip(refund.iter_mut()) {
+                    let kept = coin.amount.mul_floor(accepted);
+                    refund.amount = coin.amount - kept;
+                    coin.amount = kept;
+                }
+                // the refunded part no longer needs to mint anything
+                min_out = min_out.mul_floor(accepted);
+            }
+        }
+
+        requests.push(MintRequest {
+            queue_position: MINT_QUEUE_POSITIONS
+                .may_load(deps.storage, address.clone())?
+                .unwrap_or_default(),
+            bypass_cap: config.deposit_policy == DepositPolicy::CapBypassOnly
+                && WHITELISTED_DEPOSITORS.has(deps.storage, address.clone()),
+            dollars: get_dollars(&coins, &pricing)?,
+            address,
+            deposited,
+            min_out,
+            coins,
+            refund,
+        });
+    }
+
+    // then the headroom left under the dollar cap is shared out, in deposit order or pro rata
+    let mut cap_filled = false;
+    let mut headroom = config
+        .dollar_cap
+        .map(|dollar_cap| Uint256::from(dollar_cap).saturating_sub(total_dollars_in_vault));
+
+    let pro_rata_fill = match (headroom, &config.fill_order) {
+        (Some(headroom), FillOrder::ProRata) => {
+            let requested = requests
+                .iter()
+                .filter(|r| !r.bypass_cap)
+                .try_fold(Uint256::zero(), |total, r| total.checked_add(r.dollars))?;
+            Some(fill_ratio(headroom, requested))
+        }
+        _ => None,
+    };
+
+    if config.fill_order == FillOrder::FirstCome {
+        requests.sort_by_key(|r| r.queue_position);
+    }
 
     // for each account to mint we will calculate their dollar value to determine the amount of tokens to mint
-    for (address, (coins, min_out)) in entries {
-        let dollars_asset0 = coins[0].amount.checked_mul(pricing.price0)?;
+    for request in requests {
+        let MintRequest {
+            address,
+            deposited,
+            min_out,
+            mut coins,
+            mut refund,
+            dollars,
+            bypass_cap,
+            ..
+        } = request;
+
+        let fill = match (pro_rata_fill, headroom) {
+            _ if bypass_cap => Decimal::one(),
+            (Some(fill), _) => fill,
+            (None, Some(headroom)) => fill_ratio(headroom, dollars),
+            (None, None) => Decimal::one(),
+        };
+        cap_filled |= fill < Decimal::one();
 
-        let dollars_asset1 = coins[1].amount.checked_mul(pricing.price1)?;
+        let mut unfilled = vec![coin(0, &coins[0].denom), coin(0, &coins[1].denom)];
+        for (coin, unfilled) in coins.iter_mut().zip(unfilled.iter_mut()) {
+            let kept = coin.amount.mul_floor(fill);
+            unfilled.amount = coin.amount - kept;
+            coin.amount = kept;
+        }
 
-        let total_dollars_address = dollars_asset0.checked_add(dollars_asset1)?;
+        let queue_unfilled =
+            !config.refund_unfilled && unfilled.iter().any(|c| !c.amount.is_zero());
 
-        let to_mint = total_dollars_address
-            .checked_div(pricing.vault_price)
-            .unwrap();
+        // nothing fits under the cap, the whole deposit waits for the next round
+        if queue_unfilled && coins.iter().all(|c| c.amount.is_zero()) {
+            attributes.push(attr("address", address.to_string()));
+            attributes.push(attr("queued", format!("{},{}", unfilled[0], unfilled[1])));
+            continue;
+        }
+
+        if config.refund_unfilled {
+            refund[0].amount += unfilled[0].amount;
+            refund[1].amount += unfilled[1].amount;
+        }
+
+        // shares of the deposit that go back to the account or wait for the next round
+        let deposited_dollars = get_dollars(&deposited, &pricing)?;
+        let refunded_share = fill_ratio(get_dollars(&refund, &pricing)?, deposited_dollars);
+        let queued_share = if queue_unfilled {
+            fill_ratio(get_dollars(&unfilled, &pricing)?, deposited_dollars)
+        } else {
+            Decimal::zero()
+        };
+        refund.retain(|c| !c.amount.is_zero());
+
+        // the fee is withheld from the deposit before pricing it, so fewer tokens are minted
+        let fees = [
+            coin(
+                coins[0].amount.mul_floor(deposit_fee).u128(),
+                &coins[0].denom,
+            ),
+            coin(
+                coins[1].amount.mul_floor(deposit_fee).u128(),
+                &coins[1].denom,
+            ),
+        ];
+
+        let total_dollars_address = get_dollars(
+            &[
+                coin((coins[0].amount - fees[0].amount).u128(), &coins[0].denom),
+                coin((coins[1].amount - fees[1].amount).u128(), &coins[1].denom),
+            ],
+            &pricing,
+        )?;
+
+        let to_mint = pricing.to_shares(total_dollars_address)?;
+
+        // slippage is checked against the filled part of the deposit only
+        let filled_min_out = min_out.mul_floor(fill);
 
         attributes.push(attr("address", address.to_string()));
         attributes.push(attr("minted", to_mint.to_string()));
         attributes.push(attr("deposited", format!("{},{}", coins[0], coins[1])));
+        attributes.push(attr("fee", format!("{},{}", fees[0], fees[1])));
+        for coin in &refund {
+            attributes.push(attr("refunded", coin.to_string()));
+        }
 
         // we only process the mint if it's within the user's defined slippage, however in the case that
         // min_out is set to 0 and 0 tokens are minted the deposit will be taken uncredited
-        if to_mint >= min_out {
-            pending_assets[0].amount -= coins[0].amount;
-            pending_assets[1].amount -= coins[1].amount;
-            ACCOUNTS_PENDING_MINT.remove(deps.storage, address.clone());
+        if to_mint >= filled_min_out {
+            pending_assets[0].amount -= deposited[0].amount;
+            pending_assets[1].amount -= deposited[1].amount;
+
+            // the unfilled remainder stays in queue and keeps its place in line
+            if queue_unfilled {
+                attributes.push(attr("queued", format!("{},{}", unfilled[0], unfilled[1])));
+                pending_assets[0].amount += unfilled[0].amount;
+                pending_assets[1].amount += unfilled[1].amount;
+                ACCOUNTS_PENDING_MINT.save(
+                    deps.storage,
+                    address.clone(),
+                    &(unfilled, min_out - filled_min_out),
+                )?;
+            } else {
+                ACCOUNTS_PENDING_MINT.remove(deps.storage, address.clone());
+                MINT_QUEUE_POSITIONS.remove(deps.storage, address.clone());
+            }
+            release_allocation(deps.storage, &address, refunded_share, queued_share)?;
+
+            if !refund.is_empty() {
+                track_outflow(deps.storage, &refund)?;
+                messages.push(
+                    BankMsg::Send {
+                        to_address: address.to_string(),
+                        amount: refund,
+                    }
+                    .into(),
+                );
+            }
+
+            if fees_to_commission {
+                commission_rewards[0].amount += fees[0].amount;
+                commission_rewards[1].amount += fees[1].amount;
+            } else {
+                // fees left in the vault still count towards the cap
+                total_dollars_in_vault =
+                    total_dollars_in_vault.checked_add(get_dollars(&fees, &pricing)?)?;
+            }
 
             if !to_mint.is_zero() {
                 messages.push(
                     MsgMint {
                         sender: env.contract.address.to_string(),
                         amount: Some(osmosis_std::types::cosmos::base::v1beta1::Coin {
-                            denom: VAULT_DENOM.load(deps.storage)?,
+                            denom: vault_denom.clone(),
                             amount: to_mint.to_string(),
                         }),
                         mint_to_address: address.to_string(),
@@ -1701,13 +3193,19 @@ fn process_mints(
                 );
             }
 
+            let filled_dollars = get_dollars(&coins, &pricing)?;
+            headroom = headroom.map(|headroom| headroom.saturating_sub(filled_dollars));
+
+            total_minted += to_mint;
+            total_dollars_in_vault = total_dollars_in_vault.checked_add(total_dollars_address)?;
+
         // otherwise we skip processing of this mint and leave it in queue
         } else {
-            attributes.push(attr("slippage", format!("\"{to_mint}\",\"{min_out}\"")));
+            attributes.push(attr(
+                "slippage",
+                format!("\"{to_mint}\",\"{filled_min_out}\""),
+            ));
         }
-
-        total_minted += to_mint;
-        total_dollars_in_vault = total_dollars_in_vault.checked_add(total_dollars_address)?;
     }
 
     attributes.push(attr("total_minted", total_minted.to_string()));
@@ -1717,35 +3215,57 @@ fn process_mints(
     SUPPLY.save(deps.storage, &(supply.checked_add(total_minted)?))?;
 
     ASSETS_PENDING_MINT.save(deps.storage, &pending_assets)?;
+    COMMISSION_REWARDS.save(deps.storage, &commission_rewards)?;
 
-    // Check that we are not over the vault cap, if that's the case, we will flag it to halt joins until under cap again
+    // Check that we are not over the vault cap, if that's the case, we will flag it to halt joins until under cap again.
+    // Deposits that didn't fit mean the headroom left is only rounding dust
     if let Some(dollar_cap) = config.dollar_cap {
-        CAP_REACHED.save(deps.storage, &(total_dollars_in_vault >= dollar_cap))?;
+        CAP_REACHED.save(
+            deps.storage,
+            &(cap_filled || total_dollars_in_vault >= Uint256::from(dollar_cap)),
+        )?;
     }
 
     Ok((messages, attributes))
 }
 
 fn process_burns(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: &Env,
 ) -> Result<(Vec<CosmosMsg>, Vec<Attribute>), ContractError> {
+    let (mut messages, mut attributes) = accrue_management_fee(deps.storage, env)?;
+
     let exits: Vec<(Addr, Uint128)> = ACCOUNTS_PENDING_BURN
         .range(deps.storage, None, None, Order::Ascending)
         .filter_map(Result::ok)
         .collect();
 
     if exits.is_empty() {
-        return Ok((vec![], vec![]));
+        return Ok((messages, attributes));
     }
 
     let (total_asset0, total_asset1) =
         get_vault_balances(&deps.as_ref(), &env.contract.address.to_string(), true)?;
+    // read before the payouts are tracked as outflows, so they are only counted once
+    let (liquid_asset0, liquid_asset1) =
+        get_vault_balances(&deps.as_ref(), &env.contract.address.to_string(), false)?;
+
+    // a terminated vault must always let users out, so the price is only watched while it runs
+    if !TERMINATED.load(deps.storage)? {
+        if let Some(breaker_attrs) = check_circuit_breaker(
+            deps.branch(),
+            env,
+            &total_asset0.amount,
+            &total_asset1.amount,
+        )? {
+            attributes.extend(breaker_attrs);
+            return Ok((messages, attributes));
+        }
+    }
 
     let vault_assets = VAULT_ASSETS.load(deps.storage)?;
 
-    let mut messages: Vec<CosmosMsg> = vec![];
-    let mut attributes = vec![attr("action", "banana_vault_burn")];
+    attributes.push(attr("action", "banana_vault_burn"));
 
     let mut total_burned = Uint128::zero();
     let mut distributed_vault_tokens = [
@@ -1753,17 +3273,38 @@ fn process_burns(
         coin(0, vault_assets.1.denom.clone()),
     ];
     let supply = SUPPLY.load(deps.storage)?;
+    let virtual_supply = supply.checked_add(Uint128::new(VIRTUAL_SHARES))?;
+
+    let withdraw_fee = get_withdraw_fee(deps.storage)?;
+    let fees_to_commission =
+        CONFIG.load(deps.storage)?.fee_destination == Some(FeeDestination::Commission);
+    let mut commission_rewards = COMMISSION_REWARDS.load(deps.storage)?;
 
     // for each address waiting for burn, calculate the funds to to withdraw
     for (address, to_burn) in &exits {
-        let ratio = Decimal::new(*to_burn).checked_div(Decimal::new(supply))?;
         let mut amount_to_send = vec![
             coin(0, vault_assets.0.denom.clone()),
             coin(0, vault_assets.1.denom.clone()),
         ];
 
-        let amount_to_send_asset0 = total_asset0.amount.mul_floor(ratio);
-        let amount_to_send_asset1 = total_asset1.amount.mul_floor(ratio);
+        // rounded down in favour of the vault
+        let share_asset0 = total_asset0.amount.multiply_ratio(*to_burn, virtual_supply);
+        let share_asset1 = total_asset1.amount.multiply_ratio(*to_burn, virtual_supply);
+
+        // the fee is withheld from the payout
+        let fees = [
+            coin(
+                share_asset0.mul_floor(withdraw_fee).u128(),
+                &total_asset0.denom,
+            ),
+            coin(
+                share_asset1.mul_floor(withdraw_fee).u128(),
+                &total_asset1.denom,
+            ),
+        ];
+
+        let amount_to_send_asset0 = share_asset0 - fees[0].amount;
+        let amount_to_send_asset1 = share_asset1 - fees[1].amount;
 
         distributed_vault_tokens[0].amount += amount_to_send_asset0;
         amount_to_send[0].amount += amount_to_send_asset0;
@@ -1771,10 +3312,19 @@ fn process_burns(
         distributed_vault_tokens[1].amount += amount_to_send_asset1;
         amount_to_send[1].amount += amount_to_send_asset1;
 
+        // fees sent to the commission leave the vault as well
+        if fees_to_commission {
+            distributed_vault_tokens[0].amount += fees[0].amount;
+            distributed_vault_tokens[1].amount += fees[1].amount;
+            commission_rewards[0].amount += fees[0].amount;
+            commission_rewards[1].amount += fees[1].amount;
+        }
+
         amount_to_send.retain(|c| !c.amount.is_zero());
 
         // note: in the case that 0 tokens are withdrawn, the vault tokens will still be burned
         if !amount_to_send.is_empty() {
+            track_outflow(deps.storage, &amount_to_send)?;
             messages.push(
                 BankMsg::Send {
                     to_address: address.to_string(),
@@ -1789,12 +3339,11 @@ fn process_burns(
         for amount in amount_to_send {
             attributes.push(attr("received", format!("{}", amount)));
         }
+        attributes.push(attr("fee", format!("{},{}", fees[0], fees[1])));
 
         total_burned += to_burn;
     }
 
-    let (liquid_asset0, liquid_asset1) =
-        get_vault_balances(&deps.as_ref(), &env.contract.address.to_string(), false)?;
     if distributed_vault_tokens[0].amount > liquid_asset0.amount
         || distributed_vault_tokens[1].amount > liquid_asset1.amount
     {
@@ -1819,42 +3368,241 @@ fn process_burns(
 
     // remove burned tokens from the supply
     SUPPLY.save(deps.storage, &(supply.checked_sub(total_burned)?))?;
+    COMMISSION_REWARDS.save(deps.storage, &commission_rewards)?;
 
     // clear the pending accounts
     ACCOUNTS_PENDING_BURN.clear(deps.storage);
 
-    // if all tokens are burned, we can close the vault
-    if supply == total_burned {
+    // if all tokens but the locked ones are burned, we can close the vault
+    if supply == total_burned.checked_add(LOCKED_SHARES.load(deps.storage)?)? {
         TERMINATED.save(deps.storage, &true)?;
 
     // otherwise check if we are back under the deposit cap
     } else if let Some(dollar_cap) = CONFIG.load(deps.storage)?.dollar_cap {
-        let (current_price_asset0, current_price_asset1) = get_asset_prices(&deps.as_ref(), env)?;
+        let (current_price_asset0, current_price_asset1) =
+            get_asset_prices(&deps.as_ref(), env, PriceUse::Valuation)?;
 
-        let dollars_asset0 = (total_asset0
-            .amount
-            .checked_sub(distributed_vault_tokens[0].amount)?)
+        let dollars_asset0 = Uint256::from(
+            total_asset0
+                .amount
+                .checked_sub(distributed_vault_tokens[0].amount)?,
+        )
         .checked_mul(current_price_asset0)?;
 
-        let dollars_asset1 = (total_asset1
-            .amount
-            .checked_sub(distributed_vault_tokens[1].amount)?)
+        let dollars_asset1 = Uint256::from(
+            total_asset1
+                .amount
+                .checked_sub(distributed_vault_tokens[1].amount)?,
+        )
         .checked_mul(current_price_asset1)?;
 
         CAP_REACHED.save(
             deps.storage,
-            &(dollars_asset0.checked_add(dollars_asset1)? >= dollar_cap),
+            &(dollars_asset0.checked_add(dollars_asset1)? >= Uint256::from(dollar_cap)),
         )?;
     }
 
     Ok((messages, attributes))
 }
 
+// Compares the vault price with the last processed one. If it moved more than the threshold within the window,
+// mints and burns are paused and the breaker attributes are returned so nobody gets settled at a bad price.
+// The mid price is watched, so a wide confidence interval alone can't trip it
+fn check_circuit_breaker(
+    deps: DepsMut,
+    env: &Env,
+    amount0: &Uint128,
+    amount1: &Uint128,
+) -> Result<Option<Vec<Attribute>>, ContractError> {
+    let Some(breaker) = CONFIG.load(deps.storage)?.circuit_breaker else {
+        return Ok(None);
+    };
+
+    let vault_price =
+        get_vault_pricing(&deps.as_ref(), env, amount0, amount1, PriceUse::Valuation)?
+            .vault_price();
+    let now = env.block.time.seconds();
+
+    if let Some(last) = LAST_VAULT_PRICE.may_load(deps.storage)? {
+        // a move too large to represent is certainly over the threshold
+        let change = vault_price
+            .abs_diff(last.price)
+            .checked_div(last.price)
+            .unwrap_or(Decimal256::MAX);
+
+        if now <= last.time + breaker.window && change > Decimal256::from(breaker.threshold) {
+            let mut paused = PAUSED.load(deps.storage)?;
+            paused.mints = true;
+            paused.burns = true;
+            PAUSED.save(deps.storage, &paused)?;
+
+            return Ok(Some(vec![
+                attr("action", "banana_vault_circuit_breaker"),
+                attr("last_price", last.price.to_string()),
+                attr("price", vault_price.to_string()),
+                attr("change", change.to_string()),
+            ]));
+        }
+    }
+
+    LAST_VAULT_PRICE.save(
+        deps.storage,
+        &PriceSnapshot {
+            price: vault_price,
+            time: now,
+        },
+    )?;
+
+    Ok(None)
+}
+
+// Splits an amount across the commission receivers by weight. The last receiver gets the rounding remainder
+fn split_commission(receivers: &[CommissionReceiver], amount: Uint128) -> Vec<(Addr, Uint128)> {
+    let mut remaining = amount;
+    let mut shares = vec![];
+
+    for (i, receiver) in receivers.iter().enumerate() {
+        let share = if i == receivers.len() - 1 {
+            remaining
+        } else {
+            amount.mul_floor(receiver.weight).min(remaining)
+        };
+        remaining -= share;
+        shares.push((receiver.address.clone(), share));
+    }
+
+    shares
+}
+
+// One bank message per commission receiver with their share of each coin
+fn prepare_commission_payouts(
+    storage: &dyn Storage,
+    commissions: Vec<Coin>,
+) -> StdResult<Vec<CosmosMsg>> {
+    let receivers = COMMISSION_RECEIVERS.load(storage)?;
+    let mut payouts: Vec<Vec<Coin>> = vec![vec![]; receivers.len()];
+
+    for commission in commissions {
+        for (i, (_, amount)) in split_commission(&receivers, commission.amount)
+            .into_iter()
+            .enumerate()
+        {
+            if !amount.is_zero() {
+                payouts[i].push(coin(amount.u128(), commission.denom.clone()));
+            }
+        }
+    }
+
+    Ok(receivers
+        .into_iter()
+        .zip(payouts)
+        .filter(|(_, amount)| !amount.is_empty())
+        .map(|(receiver, amount)| {
+            BankMsg::Send {
+                to_address: receiver.address.into_string(),
+                amount,
+            }
+            .into()
+        })
+        .collect())
+}
+
+// No withdraw fee is charged once the vault is terminated
+fn get_withdraw_fee(storage: &dyn Storage) -> StdResult<Decimal> {
+    if TERMINATED.load(storage)? {
+        return Ok(Decimal::zero());
+    }
+
+    Ok(CONFIG.load(storage)?.withdraw_fee.unwrap_or_default())
+}
+
+// Mints the management fee accrued since the last accrual to the commission receivers.
+// Nothing accrues once the vault is terminated.
+fn accrue_management_fee(
+    storage: &mut dyn Storage,
+    env: &Env,
+) -> Result<(Vec<CosmosMsg>, Vec<Attribute>), ContractError> {
+    let now = env.block.time.seconds();
+    let last_accrual = LAST_FEE_ACCRUAL.load(storage)?;
+    let management_fee = CONFIG.load(storage)?.management_fee.unwrap_or_default();
+
+    if management_fee.is_zero() || TERMINATED.load(storage)? {
+        LAST_FEE_ACCRUAL.save(storage, &now)?;
+        return Ok((vec![], vec![]));
+    }
+
+    let elapsed = Decimal::from_ratio(now.saturating_sub(last_accrual), SECONDS_PER_YEAR);
+    let supply = SUPPLY.load(storage)?;
+    let fee = supply.mul_floor(management_fee.checked_mul(elapsed)?);
+
+    // the accrual time is only moved forward when something is minted, so that frequent calls
+    // don't round the fee away
+    if fee.is_zero() {
+        return Ok((vec![], vec![]));
+    }
+
+    SUPPLY.save(storage, &supply.checked_add(fee)?)?;
+    LAST_FEE_ACCRUAL.save(storage, &now)?;
+
+    let vault_denom = VAULT_DENOM.load(storage)?;
+    let mint_msgs: Vec<CosmosMsg> = split_commission(&COMMISSION_RECEIVERS.load(storage)?, fee)
+        .into_iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|(address, amount)| {
+            MsgMint {
+                sender: env.contract.address.to_string(),
+                amount: Some(osmosis_std::types::cosmos::base::v1beta1::Coin {
+                    denom: vault_denom.clone(),
+                    amount: amount.to_string(),
+                }),
+                mint_to_address: address.to_string(),
+            }
+            .into()
+        })
+        .collect();
+
+    Ok((
+        mint_msgs,
+        vec![
+            attr("action", "banana_vault_accrue_fees"),
+            attr("management_fee", fee.to_string()),
+        ],
+    ))
+}
+
 struct Pricing {
-    total_dollars: Uint128,
-    price0: Uint128,
-    price1: Uint128,
-    vault_price: Uint128,
+    total_dollars: Uint256,
+    price0: Uint256,
+    price1: Uint256,
+    // supply and the dollars backing it, virtual shares included
+    virtual_supply: Uint256,
+    virtual_dollars: Uint256,
+}
+
+impl Pricing {
+    // dollars per vault token unit
+    fn vault_price(&self) -> Decimal256 {
+        Decimal256::checked_from_ratio(self.virtual_dollars, self.virtual_supply)
+            .unwrap_or(Decimal256::MAX)
+    }
+
+    // vault tokens worth the dollars, rounded down in favour of the vault
+    fn to_shares(&self, dollars: Uint256) -> StdResult<Uint128> {
+        Ok(Uint128::try_from(
+            dollars
+                .checked_mul(self.virtual_supply)?
+                .checked_div(self.virtual_dollars)?,
+        )?)
+    }
+
+    // dollars the vault tokens are worth, rounded up so that holdings are never undercounted
+    fn to_dollars(&self, shares: Uint128) -> StdResult<Uint256> {
+        let numerator = Uint256::from(shares).checked_mul(self.virtual_dollars)?;
+        Ok(numerator
+            .checked_add(self.virtual_supply)?
+            .checked_sub(Uint256::one())?
+            .checked_div(self.virtual_supply)?)
+    }
 }
 
 fn get_vault_pricing(
@@ -1862,141 +3610,522 @@ fn get_vault_pricing(
     env: &Env,
     amount0: &Uint128,
     amount1: &Uint128,
+    price_use: PriceUse,
 ) -> StdResult<Pricing> {
-    let (price0, price1) = get_asset_prices(deps, env)?;
+    let (price0, price1) = get_asset_prices(deps, env, price_use)?;
 
-    let dollars0 = amount0.checked_mul(price0)?;
-    let dollars1 = amount1.checked_mul(price1)?;
+    let dollars0 = Uint256::from(*amount0).checked_mul(price0)?;
+    let dollars1 = Uint256::from(*amount1).checked_mul(price1)?;
 
     let total_dollars = dollars0.checked_add(dollars1)?;
 
+    // the virtual shares come with their value at the initial price
+    let virtual_shares = Uint256::from(VIRTUAL_SHARES);
+
     Ok(Pricing {
         total_dollars,
         price0,
         price1,
-        vault_price: total_dollars.checked_div(SUPPLY.load(deps.storage)?)?,
+        virtual_supply: Uint256::from(SUPPLY.load(deps.storage)?).checked_add(virtual_shares)?,
+        virtual_dollars: total_dollars
+            .checked_add(virtual_shares.checked_mul(Uint256::from(INITIAL_VAULT_PRICE))?)?,
     })
 }
 
+// What prices are used for, which decides the side of the confidence interval they are taken at
+#[derive(Clone, Copy, PartialEq)]
+enum PriceUse {
+    Mint,
+    Burn,
+    Valuation,
+}
+
 // gets the up-to-date prices for each vault asset
-fn get_asset_prices(deps: &Deps, env: &Env) -> StdResult<(Uint128, Uint128)> {
+fn get_asset_prices(deps: &Deps, env: &Env, price_use: PriceUse) -> StdResult<(Uint256, Uint256)> {
     let config = CONFIG.load(deps.storage)?;
     let vault_assets = VAULT_ASSETS.load(deps.storage)?;
-    let current_time = env.block.time.seconds();
 
-    let price_querier: &dyn PriceQuerier =
-        if config.pyth_contract_address == Addr::unchecked(PYTH_DUMMY_CONTRACT_ADDRESS) {
-            &MockPriceQuerier
-        } else {
-            &PythQuerier
-        };
+    let mut prices = vec![];
+    for asset in [&vault_assets.0, &vault_assets.1] {
+        let quote = fresh_price(
+            asset_price_querier(asset, &config).query_asset_price(
+                deps,
+                env,
+                &config,
+                asset.decimals,
+            )?,
+            &asset.denom,
+            asset_price_expiry(asset, &config),
+        )?;
 
-    let current_price_asset0 = price_querier.query_asset_price(
-        &deps.querier,
-        config.pyth_contract_address.clone(),
-        vault_assets.0.price_identifier,
-        current_time as i64,
-        config.price_expiry,
-        vault_assets.0.decimals,
-    )?;
+        prices.push(bounded_price(&quote, &config, price_use, &asset.denom)?);
+    }
 
-    let current_price_asset1 = price_querier.query_asset_price(
-        &deps.querier,
-        config.pyth_contract_address,
-        vault_assets.1.price_identifier,
-        current_time as i64,
-        config.price_expiry,
-        vault_assets.1.decimals,
+    // dollar prices become prices in the numeraire, a whole numeraire token being worth 10^26 units
+    let (numeraire_querier, numeraire_denom, numeraire_decimals, numeraire_expiry) =
+        match &config.numeraire {
+            Numeraire::Usd => return Ok((prices[0], prices[1])),
+            Numeraire::Asset0 => (
+                asset_price_querier(&vault_assets.0, &config),
+                &vault_assets.0.denom,
+                vault_assets.0.decimals,
+                asset_price_expiry(&vault_assets.0, &config),
+            ),
+            Numeraire::Asset1 => (
+                asset_price_querier(&vault_assets.1, &config),
+                &vault_assets.1.denom,
+                vault_assets.1.decimals,
+                asset_price_expiry(&vault_assets.1, &config),
+            ),
+            Numeraire::External {
+                denom,
+                decimals,
+                source,
+            } => (
+                price_querier(source, denom, config.price_expiry, &config),
+                denom,
+                *decimals,
+                config.price_expiry,
+            ),
+        };
+    let numeraire_quote = fresh_price(
+        numeraire_querier.query_asset_price(deps, env, &config, numeraire_decimals)?,
+        numeraire_denom,
+        numeraire_expiry,
     )?;
 
-    Ok((current_price_asset0, current_price_asset1))
+    // asset prices are divided by the numeraire price, so its bound is taken on the opposite side
+    let numeraire_use = match price_use {
+        PriceUse::Mint => PriceUse::Burn,
+        PriceUse::Burn => PriceUse::Mint,
+        PriceUse::Valuation => PriceUse::Valuation,
+    };
+    let numeraire_price = bounded_price(&numeraire_quote, &config, numeraire_use, numeraire_denom)?
+        .checked_mul(Uint256::from(10_u128).checked_pow(numeraire_decimals)?)?;
+
+    let unit = Uint256::from(10_u128).pow(DOLLAR_EXPONENT as u32);
+    let to_numeraire = |price: Uint256| {
+        price
+            .checked_multiply_ratio(unit, numeraire_price)
+            .map_err(|e| StdError::generic_err(e.to_string()))
+    };
+
+    Ok((to_numeraire(prices[0])?, to_numeraire(prices[1])?))
 }
 
+// Rejects quotes that are too uncertain and picks the side of the confidence interval for the use
+fn bounded_price(
+    quote: &AssetPrice,
+    config: &Config,
+    price_use: PriceUse,
+    denom: &str,
+) -> StdResult<Uint256> {
+    let not_positive =
+        || StdError::generic_err(format!("Oracle price for {denom} must be positive"));
+
+    if quote.price.is_zero() {
+        return Err(not_positive());
+    }
+
+    if let Some(max_ratio) = config.max_confidence_ratio {
+        let ratio = Decimal256::checked_from_ratio(quote.conf, quote.price)
+            .map_err(|e| StdError::generic_err(e.to_string()))?;
+        if ratio > Decimal256::from(max_ratio) {
+            return Err(StdError::generic_err(format!(
+                "Oracle confidence interval for {denom} is too wide"
+            )));
+        }
+    }
+
+    let lower = quote.price.saturating_sub(quote.conf);
+    let upper = quote.price.checked_add(quote.conf)?;
+    let price = match (&config.price_bound, price_use) {
+        (PriceBound::LowerForMints, PriceUse::Mint)
+        | (PriceBound::UpperForMints, PriceUse::Burn) => lower,
+        (PriceBound::LowerForMints, PriceUse::Burn)
+        | (PriceBound::UpperForMints, PriceUse::Mint) => upper,
+        _ => quote.price,
+    };
+
+    if price.is_zero() {
+        return Err(not_positive());
+    }
+
+    Ok(price)
+}
+
+// An oracle quote for the smallest unit of an asset, in dollars
+pub struct AssetPrice {
+    pub price: Uint256,
+    // Confidence interval around the price
+    pub conf: Uint256,
+}
+
+// Sources without a fresh quote return None, which lets a fallback take over. Errors are final
 pub trait PriceQuerier {
     fn query_asset_price(
         &self,
-        querier: &QuerierWrapper,
-        contract_address: Addr,
-        identifier: PriceIdentifier,
-        time: i64,
-        expiry: u64,
-        exponent: u32,
-    ) -> StdResult<Uint128>;
+        deps: &Deps,
+        env: &Env,
+        config: &Config,
+        decimals: u32,
+    ) -> StdResult<Option<AssetPrice>>;
+}
+
+fn asset_price_expiry(asset: &VaultAsset, config: &Config) -> u64 {
+    asset.price_expiry.unwrap_or(config.price_expiry)
+}
+
+// A quote is needed by now, so a stale source is an error
+fn fresh_price(quote: Option<AssetPrice>, denom: &str, expiry: u64) -> StdResult<AssetPrice> {
+    quote.ok_or_else(|| {
+        StdError::generic_err(format!(
+            "Price quote for {denom} is older than {expiry} seconds, please update"
+        ))
+    })
+}
+
+// Builds the querier of an asset, falling back to the secondary source if the primary is stale
+fn asset_price_querier(asset: &VaultAsset, config: &Config) -> Box<dyn PriceQuerier> {
+    let expiry = asset_price_expiry(asset, config);
+    let primary = price_querier(
+        asset.oracle.as_ref().unwrap_or(&PriceSource::Pyth {
+            price_identifier: asset.price_identifier,
+        }),
+        &asset.denom,
+        expiry,
+        config,
+    );
+
+    match &asset.fallback {
+        Some(fallback) => Box::new(FallbackQuerier {
+            primary,
+            fallback: price_querier(fallback, &asset.denom, expiry, config),
+        }),
+        None => primary,
+    }
+}
+
+fn price_querier(
+    source: &PriceSource,
+    denom: &str,
+    expiry: u64,
+    config: &Config,
+) -> Box<dyn PriceQuerier> {
+    match source {
+        PriceSource::Pyth { price_identifier } => {
+            if config.pyth_contract_address == Addr::unchecked(PYTH_DUMMY_CONTRACT_ADDRESS) {
+                Box::new(MockPriceQuerier {
+                    identifier: *price_identifier,
+                })
+            } else {
+                Box::new(PythQuerier {
+                    identifier: *price_identifier,
+                    expiry,
+                })
+            }
+        }
+        PriceSource::Twap {
+            pool_id,
+            quote_denom,
+            quote_decimals,
+            quote_source,
+            window,
+            kind,
+        } => Box::new(OsmosisTwapQuerier {
+            pool_id: *pool_id,
+            base_denom: denom.to_string(),
+            quote_denom: quote_denom.clone(),
+            quote_decimals: *quote_decimals,
+            quote: price_querier(quote_source, quote_denom, expiry, config),
+            window: *window,
+            kind: kind.clone(),
+        }),
+        PriceSource::Fixed { price } => Box::new(FixedPriceQuerier { price: *price }),
+        PriceSource::Median {
+            sources,
+            min_quotes,
+        } => Box::new(MedianQuerier {
+            min_quotes: *min_quotes as usize,
+            sources: sources
+                .iter()
+                .map(|source| price_querier(source, denom, expiry, config))
+                .collect(),
+        }),
+    }
+}
+
+// Checks that a price source can produce a quote
+fn verify_price_source(source: &PriceSource, denom: &str) -> Result<(), ContractError> {
+    let valid = match source {
+        PriceSource::Pyth { .. } => true,
+        PriceSource::Twap {
+            quote_denom,
+            quote_source,
+            window,
+            ..
+        } => {
+            verify_price_source(quote_source, quote_denom)?;
+            *window > 0 && quote_denom != denom
+        }
+        PriceSource::Fixed { price } => !price.is_zero(),
+        PriceSource::Median {
+            sources,
+            min_quotes,
+        } => {
+            for source in sources {
+                verify_price_source(source, denom)?;
+            }
+            (1..=sources.len()).contains(&(*min_quotes as usize))
+        }
+    };
+
+    if !valid {
+        return Err(ContractError::InvalidPriceSource {
+            denom: denom.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+// Converts a feed value with its own exponent to the dollar value of the smallest unit of an asset
+fn normalize_price(value: Uint256, expo: i32, decimals: u32) -> StdResult<Uint256> {
+    let exponent = expo + DOLLAR_EXPONENT - decimals as i32;
+    let scale = Uint256::from(10_u128).checked_pow(exponent.unsigned_abs())?;
+
+    if exponent >= 0 {
+        Ok(value.checked_mul(scale)?)
+    } else {
+        Ok(value / scale)
+    }
+}
+
+struct PythQuerier {
+    identifier: PriceIdentifier,
+    expiry: u64,
 }
 
-struct PythQuerier;
 impl PriceQuerier for PythQuerier {
     fn query_asset_price(
         &self,
-        querier: &QuerierWrapper,
-        contract_address: Addr,
-        identifier: PriceIdentifier,
-        time: i64,
-        expiry: u64,
-        exponent: u32,
-    ) -> StdResult<Uint128> {
-        query_price_feed(querier, contract_address, identifier)?
-            .price_feed
-            .get_price_no_older_than(time, expiry)
-            .map_or_else(
-                || {
-                    Err(StdError::generic_err(format!(
-                        "Pyth quote is older than {expiry} seconds, please update"
-                    )))
-                },
-                |price| {
-                    Ok(Uint128::new(
-                        // convert pricing to a base 10^18 representation for precision
-                        price.price as u128 * 10_u128.pow(18) / 10_u128.pow(exponent),
-                    ))
-                },
-            )
+        deps: &Deps,
+        env: &Env,
+        config: &Config,
+        decimals: u32,
+    ) -> StdResult<Option<AssetPrice>> {
+        let Some(price) = query_price_feed(
+            &deps.querier,
+            config.pyth_contract_address.clone(),
+            self.identifier,
+        )?
+        .price_feed
+        .get_price_no_older_than(env.block.time.seconds() as i64, self.expiry) else {
+            return Ok(None);
+        };
+
+        // a negative price would wrap around when cast
+        if price.price <= 0 {
+            return Err(StdError::generic_err(format!(
+                "Pyth price for {} must be positive",
+                self.identifier
+            )));
+        }
+
+        Ok(Some(AssetPrice {
+            price: normalize_price(Uint256::from(price.price as u64), price.expo, decimals)?,
+            conf: normalize_price(Uint256::from(price.conf), price.expo, decimals)?,
+        }))
     }
 }
 
-pub struct MockPriceQuerier;
+// Prices the asset in a quote denom with the pool TWAP, then the quote denom in dollars
+struct OsmosisTwapQuerier {
+    pool_id: u64,
+    base_denom: String,
+    quote_denom: String,
+    quote_decimals: u32,
+    quote: Box<dyn PriceQuerier>,
+    window: u64,
+    kind: TwapKind,
+}
 
-impl PriceQuerier for MockPriceQuerier {
+impl PriceQuerier for OsmosisTwapQuerier {
     fn query_asset_price(
         &self,
-        _querier: &QuerierWrapper,
-        _contract_address: Addr,
-        identifier: PriceIdentifier,
-        _time: i64,
-        _expiry: u64,
-        exponent: u32,
-    ) -> StdResult<Uint128> {
-        if identifier
-            == PriceIdentifier::from_hex(
-                "5867f5683c757393a0670ef0f701490950fe93fdb006d181c8265a831ac0c5c6",
-            )
-            .unwrap()
-        {
-            return Ok(Uint128::new(
-                164_243_925_u128 * 10_u128.pow(18) / 10_u128.pow(exponent),
-            ));
+        deps: &Deps,
+        env: &Env,
+        config: &Config,
+        _decimals: u32,
+    ) -> StdResult<Option<AssetPrice>> {
+        let twap_querier = TwapQuerier::new(&deps.querier);
+        let start_time = Some(Timestamp {
+            seconds: env.block.time.seconds().saturating_sub(self.window) as i64,
+            nanos: 0,
+        });
+
+        // the TWAP is the amount of quote units per base unit, so decimals are already accounted for
+        let twap = match self.kind {
+            TwapKind::Arithmetic => {
+                twap_querier
+                    .arithmetic_twap_to_now(
+                        self.pool_id,
+                        self.base_denom.clone(),
+                        self.quote_denom.clone(),
+                        start_time,
+                    )?
+                    .arithmetic_twap
+            }
+            TwapKind::Geometric => {
+                twap_querier
+                    .geometric_twap_to_now(
+                        self.pool_id,
+                        self.base_denom.clone(),
+                        self.quote_denom.clone(),
+                        start_time,
+                    )?
+                    .geometric_twap
+            }
+        };
+        let twap = Decimal256::from_str(&twap)?;
+
+        let Some(quote) = self
+            .quote
+            .query_asset_price(deps, env, config, self.quote_decimals)?
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(AssetPrice {
+            price: quote
+                .price
+                .multiply_ratio(twap.atomics(), Decimal256::one().atomics()),
+            conf: quote
+                .conf
+                .multiply_ratio(twap.atomics(), Decimal256::one().atomics()),
+        }))
+    }
+}
+
+struct FixedPriceQuerier {
+    price: Decimal,
+}
+
+impl PriceQuerier for FixedPriceQuerier {
+    fn query_asset_price(
+        &self,
+        _deps: &Deps,
+        _env: &Env,
+        _config: &Config,
+        decimals: u32,
+    ) -> StdResult<Option<AssetPrice>> {
+        Ok(Some(AssetPrice {
+            price: normalize_price(
+                self.price.atomics().into(),
+                -(Decimal::DECIMAL_PLACES as i32),
+                decimals,
+            )?,
+            conf: Uint256::zero(),
+        }))
+    }
+}
+
+// Takes the middle quote, or the average of the two middle ones for an even number of quotes.
+// Sources that fail or are stale are left out as long as enough of them quote
+struct MedianQuerier {
+    sources: Vec<Box<dyn PriceQuerier>>,
+    min_quotes: usize,
+}
+
+impl PriceQuerier for MedianQuerier {
+    fn query_asset_price(
+        &self,
+        deps: &Deps,
+        env: &Env,
+        config: &Config,
+        decimals: u32,
+    ) -> StdResult<Option<AssetPrice>> {
+        let mut quotes = vec![];
+        let mut failed = 0;
+        for source in &self.sources {
+            match source.query_asset_price(deps, env, config, decimals) {
+                Ok(Some(quote)) => quotes.push(quote),
+                Ok(None) => {}
+                Err(_) => failed += 1,
+            }
         }
-        if identifier
-            == PriceIdentifier::from_hex(
-                "b00b60f88b03a6a625a8d1c048c3f66653edf217439983d037e7222c4e612819",
-            )
-            .unwrap()
-        {
-            return Ok(Uint128::new(
-                1_031_081_328_u128 * 10_u128.pow(18) / 10_u128.pow(exponent),
-            ));
+
+        if quotes.len() < self.min_quotes {
+            // only stale sources are missing, so a fallback may still have a fresh quote
+            if failed == 0 {
+                return Ok(None);
+            }
+            return Err(StdError::generic_err(format!(
+                "Median needs {} quotes, only {} sources quoted",
+                self.min_quotes,
+                quotes.len()
+            )));
         }
-        if identifier
-            == PriceIdentifier::from_hex(
-                "ff61491a931112ddf1bd8147cd1b641375f79f5825126d665480874634fd0ace",
-            )
-            .unwrap()
+
+        quotes.sort_by_key(|quote| quote.price);
+
+        let middle = quotes.len() / 2;
+        if quotes.len() % 2 == 1 {
+            return Ok(Some(quotes.swap_remove(middle)));
+        }
+
+        let (low, high) = (&quotes[middle - 1], &quotes[middle]);
+        Ok(Some(AssetPrice {
+            price: (low.price.checked_add(high.price)?) / Uint256::from(2_u128),
+            conf: (low.conf.checked_add(high.conf)?) / Uint256::from(2_u128),
+        }))
+    }
+}
+
+struct FallbackQuerier {
+    primary: Box<dyn PriceQuerier>,
+    fallback: Box<dyn PriceQuerier>,
+}
+
+impl PriceQuerier for FallbackQuerier {
+    fn query_asset_price(
+        &self,
+        deps: &Deps,
+        env: &Env,
+        config: &Config,
+        decimals: u32,
+    ) -> StdResult<Option<AssetPrice>> {
+        match self
+            .primary
+            .query_asset_price(deps, env, config, decimals)?
         {
-            return Ok(Uint128::new(
-                278_558_964_008_u128 * 10_u128.pow(18) / 10_u128.pow(exponent),
-            ));
+            Some(quote) => Ok(Some(quote)),
+            None => self.fallback.query_asset_price(deps, env, config, decimals),
         }
+    }
+}
 
-        Ok(Uint128::zero())
+pub struct MockPriceQuerier {
+    pub identifier: PriceIdentifier,
+}
+
+impl PriceQuerier for MockPriceQuerier {
+    fn query_asset_price(
+        &self,
+        deps: &Deps,
+        _env: &Env,
+        _config: &Config,
+        decimals: u32,
+    ) -> StdResult<Option<AssetPrice>> {
+        let price = MOCK_PRICES
+            .may_load(deps.storage, self.identifier.to_hex())?
+            .ok_or_else(|| {
+                StdError::generic_err(format!("No mock price for {}", self.identifier))
+            })?;
+
+        Ok(Some(AssetPrice {
+            price: normalize_price(price.price.into(), price.expo, decimals)?,
+            conf: normalize_price(price.conf.into(), price.expo, decimals)?,
+        }))
     }
 }
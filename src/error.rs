@@ -1,6 +1,6 @@
 use std::num::ParseIntError;
 
-use cosmwasm_std::{CheckedFromRatioError, OverflowError, StdError};
+use cosmwasm_std::{CheckedFromRatioError, ConversionOverflowError, OverflowError, StdError};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,6 +17,12 @@ pub enum ContractError {
     #[error(transparent)]
     CheckedFromRatioError(#[from] CheckedFromRatioError),
 
+    #[error(transparent)]
+    ConversionOverflowError(#[from] ConversionOverflowError),
+
+    #[error("No migration from version {}", version)]
+    UnsupportedMigration { version: String },
+
     #[error("Pool {} not found", pool_id)]
     PoolNotFound { pool_id: u64 },
 
@@ -26,6 +32,60 @@ pub enum ContractError {
     #[error("Commission rate can't be set to 100% or more")]
     CommissionTooHigh,
 
+    #[error("Commission rate can't be set above the maximum of {}", max)]
+    CommissionAboveMax { max: String },
+
+    #[error("No pending commission change")]
+    NoPendingCommission,
+
+    #[error(
+        "Commission change can't be applied yet. Still {} seconds remaining",
+        seconds
+    )]
+    CommissionNotReady { seconds: u64 },
+
+    #[error("Timelock delay must be at least {} seconds", min)]
+    TimelockDelayTooShort { min: u64 },
+
+    #[error("Config change doesn't change anything")]
+    EmptyConfigChange,
+
+    #[error("Commission delay must be at least {} seconds", min)]
+    CommissionDelayTooShort { min: u64 },
+
+    #[error("Management fee can't be set to 100% or more")]
+    ManagementFeeTooHigh,
+
+    #[error("{} fee can't be set to 100% or more", fee)]
+    FeeTooHigh { fee: String },
+
+    #[error("Invalid denom metadata: {}", reason)]
+    InvalidDenomMetadata { reason: String },
+
+    #[error("Invalid subdenom {}", subdenom)]
+    InvalidSubdenom { subdenom: String },
+
+    #[error("Commission receivers must be unique, have a positive weight and add up to 100%")]
+    InvalidCommissionReceivers,
+
+    #[error("Pyth update fee of {} must be sent", fee)]
+    InsufficientUpdateFee { fee: String },
+
+    #[error("Funds are only taken to pay for pushed price updates")]
+    UnexpectedFunds,
+
+    #[error("Oracle address {} is not a contract", address)]
+    InvalidOracleAddress { address: String },
+
+    #[error("Mock prices can only be set on test vaults")]
+    MockOracleDisabled,
+
+    #[error("Invalid price source for {}", denom)]
+    InvalidPriceSource { denom: String },
+
+    #[error("Seed deposit is too small to mint the initial vault tokens")]
+    SeedTooSmall,
+
     #[error("Funds must be sent to participate in the vault")]
     NoFunds,
 
@@ -44,12 +104,39 @@ pub enum ContractError {
     #[error("The assets of the config cannot change")]
     CannotChangeAssets,
 
+    #[error("The numeraire of the vault cannot change")]
+    CannotChangeNumeraire,
+
     #[error("Trying to add more than available {}{} to position.", amount, asset)]
     CannotAddMoreThanAvailableForAsset { asset: String, amount: String },
 
     #[error("Operation unauthorized")]
     Unauthorized,
 
+    #[error("Operation unauthorized, {} role required", role)]
+    MissingRole { role: String },
+
+    #[error("Address {} already has the {} role", address, role)]
+    RoleAlreadyGranted { address: String, role: String },
+
+    #[error("Address {} doesn't have the {} role", address, role)]
+    RoleNotGranted { address: String, role: String },
+
+    #[error("Queued change {} not found", id)]
+    QueuedChangeNotFound { id: u64 },
+
+    #[error(
+        "Queued change can't be executed yet. Still {} seconds remaining",
+        seconds
+    )]
+    QueuedChangeNotReady { seconds: u64 },
+
+    #[error("No pending ownership transfer")]
+    NoPendingOwner,
+
+    #[error("Ownership transfer expired")]
+    OwnershipTransferExpired,
+
     #[error("Cannot swap more than available of {}", denom)]
     CannotSwapMoreThanAvailable { denom: String },
 
@@ -59,8 +146,20 @@ pub enum ContractError {
     #[error("Vault cap reached, join not allowed until vault is under cap again")]
     CapReached,
 
-    #[error("Vault halted, nobody can join or leave until unhalted")]
-    VaultHalted,
+    #[error("Only whitelisted addresses can deposit")]
+    NotWhitelisted,
+
+    #[error("Deposits require an allocation proof")]
+    MissingAllocation,
+
+    #[error("Invalid merkle root or proof")]
+    InvalidMerkleProof,
+
+    #[error("Deposit exceeds allocation. Only {} dollars remaining", remaining)]
+    AllocationExceeded { remaining: String },
+
+    #[error("Vault {} paused until resumed by the owner", action)]
+    Paused { action: String },
 
     #[error("Vault closed, nobody can join and funds returned to users")]
     VaultClosed,
@@ -68,6 +167,9 @@ pub enum ContractError {
     #[error("Can't unlock vault yet. Still {} seconds remaining", seconds)]
     CantUnlockYet { seconds: u64 },
 
+    #[error("Update interval must be between {} and {} seconds", min, max)]
+    InvalidUpdateInterval { min: u64, max: u64 },
+
     #[error("No position found")]
     NoPositionsOpen,
 
@@ -103,4 +205,7 @@ pub enum ContractError {
 
     #[error("Nothing to claim")]
     CannotClaim,
+
+    #[error("Unknown reply id {}", id)]
+    UnknownReplyId { id: u64 },
 }
@@ -0,0 +1,167 @@
+use crate::{
+    contract::{
+        seed_mock_prices, verify_config, DEFAULT_UPDATE_INTERVAL, MIN_COMMISSION_DELAY,
+        MIN_TIMELOCK_DELAY, PYTH_DUMMY_CONTRACT_ADDRESS,
+    },
+    error::ContractError,
+    msg::MigrateMsg,
+    state::{
+        CommissionReceiver, Config, DepositPolicy, FillOrder, Numeraire, Pauses, PriceBound,
+        COMMISSION_RATE, COMMISSION_RECEIVERS, CONFIG, LAST_FEE_ACCRUAL, LAST_UPDATE,
+        LOCKED_SHARES, MAX_COMMISSION, PAUSED, TRACKED_BALANCES, VAULT_ASSETS,
+    },
+};
+use cosmwasm_std::{attr, Attribute, Decimal, DepsMut, Env, StdResult};
+
+// A step upgrades the state written by one version to the layout of the version released after it
+type MigrationStep = fn(DepsMut, &Env, &MigrateMsg) -> Result<(), ContractError>;
+
+// Steps keyed by the version they migrate from, in release order
+const MIGRATIONS: [(&str, MigrationStep); 1] = [("0.5.0", v0_5_0::migrate)];
+
+// Runs every step from the stored version onwards, so older vaults are upgraded one release at a time
+pub fn run_migrations(
+    mut deps: DepsMut,
+    env: &Env,
+    from_version: &str,
+    current_version: &str,
+    msg: &MigrateMsg,
+) -> Result<Vec<Attribute>, ContractError> {
+    // migrating to the same version only swaps the code
+    if from_version == current_version {
+        return Ok(vec![]);
+    }
+
+    let first_step = MIGRATIONS
+        .iter()
+        .position(|(version, _)| *version == from_version)
+        .ok_or(ContractError::UnsupportedMigration {
+            version: from_version.to_string(),
+        })?;
+
+    let mut attributes = vec![];
+    for (version, step) in &MIGRATIONS[first_step..] {
+        step(deps.branch(), env, msg)?;
+        attributes.push(attr("migrated_from", *version));
+    }
+
+    Ok(attributes)
+}
+
+mod v0_5_0 {
+    use super::*;
+    use crate::state::{Metadata, TopKey};
+    use cosmwasm_schema::cw_serde;
+    use cosmwasm_std::{Addr, Uint128};
+    use cw_storage_plus::Item;
+
+    #[cw_serde]
+    struct Config {
+        metadata: Option<Metadata>,
+        min_asset0: Uint128,
+        min_asset1: Uint128,
+        min_redemption: Option<Uint128>,
+        dollar_cap: Option<Uint128>,
+        pyth_contract_address: Addr,
+        price_expiry: u64,
+        commission_receiver: Addr,
+    }
+
+    const CONFIG: Item<Config> = Item::new(TopKey::Config.as_str());
+    // replaced by the pause switches under the same key
+    const HALTED: Item<bool> = Item::new(TopKey::Paused.as_str());
+
+    pub fn migrate(deps: DepsMut, env: &Env, msg: &MigrateMsg) -> Result<(), ContractError> {
+        let old_config = CONFIG.load(deps.storage)?;
+
+        let config = super::Config {
+            metadata: old_config.metadata,
+            min_asset0: old_config.min_asset0,
+            min_asset1: old_config.min_asset1,
+            min_redemption: old_config.min_redemption,
+            dollar_cap: old_config.dollar_cap,
+            address_cap: msg.address_cap,
+            pyth_contract_address: old_config.pyth_contract_address,
+            price_expiry: old_config.price_expiry,
+            max_confidence_ratio: msg.max_confidence_ratio,
+            price_bound: msg.price_bound.clone().unwrap_or(PriceBound::Mid),
+            // caps and prices stored by v0.5.0 are in dollars
+            numeraire: Numeraire::Usd,
+            management_fee: msg.management_fee,
+            deposit_fee: msg.deposit_fee,
+            withdraw_fee: msg.withdraw_fee,
+            fee_destination: msg.fee_destination.clone(),
+            commission_delay: msg.commission_delay.unwrap_or(MIN_COMMISSION_DELAY),
+            timelock_delay: msg.timelock_delay.unwrap_or(MIN_TIMELOCK_DELAY),
+            update_interval: msg.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL),
+            circuit_breaker: msg.circuit_breaker.clone(),
+            // whitelisted addresses could always exceed the cap
+            deposit_policy: msg
+                .deposit_policy
+                .clone()
+                .unwrap_or(DepositPolicy::CapBypassOnly),
+            fill_order: msg.fill_order.clone().unwrap_or(FillOrder::FirstCome),
+            refund_unfilled: msg.refund_unfilled.unwrap_or_default(),
+        };
+
+        verify_config(&config)?;
+        super::CONFIG.save(deps.storage, &config)?;
+
+        // the mock oracle used to quote fixed prices, they now live in storage
+        if config.pyth_contract_address == PYTH_DUMMY_CONTRACT_ADDRESS {
+            seed_mock_prices(deps.storage)?;
+        }
+
+        // the single commission receiver now takes all the weight
+        COMMISSION_RECEIVERS.save(
+            deps.storage,
+            &vec![CommissionReceiver {
+                address: old_config.commission_receiver,
+                weight: Decimal::one(),
+            }],
+        )?;
+
+        // same default as instantiation, the current rate can't be raised unless a higher max is given
+        let commission = COMMISSION_RATE.load(deps.storage)?;
+        let max_commission = msg.max_commission.unwrap_or(commission);
+        if max_commission >= Decimal::percent(100) {
+            return Err(ContractError::CommissionTooHigh);
+        }
+        if commission > max_commission {
+            return Err(ContractError::CommissionAboveMax {
+                max: max_commission.to_string(),
+            });
+        }
+        MAX_COMMISSION.save(deps.storage, &max_commission)?;
+
+        // the old halt blocked both joins and exits
+        let halted = HALTED.load(deps.storage)?;
+        PAUSED.save(
+            deps.storage,
+            &Pauses {
+                mints: halted,
+                burns: halted,
+                ..Pauses::default()
+            },
+        )?;
+
+        // everything held so far counts as part of the vault
+        let vault_assets = VAULT_ASSETS.load(deps.storage)?;
+        let tracked_balances = [vault_assets.0.denom, vault_assets.1.denom]
+            .into_iter()
+            .map(|denom| deps.querier.query_balance(&env.contract.address, denom))
+            .collect::<StdResult<Vec<_>>>()?;
+        TRACKED_BALANCES.save(deps.storage, &tracked_balances)?;
+
+        // the supply was never seeded with locked shares
+        LOCKED_SHARES.save(deps.storage, &Uint128::zero())?;
+
+        // management fees only accrue from the upgrade onwards
+        LAST_FEE_ACCRUAL.save(deps.storage, &env.block.time.seconds())?;
+
+        // the old contract never refreshed the last update, so the dead man switch restarts from the upgrade
+        LAST_UPDATE.save(deps.storage, &env.block.time.seconds())?;
+
+        Ok(())
+    }
+}
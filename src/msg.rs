@@ -1,32 +1,72 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::{Addr, Coin, Decimal, Uint128};
+use cosmwasm_std::{Addr, Binary, Coin, Decimal, Decimal256, Uint128, Uint256};
 use osmosis_std::types::osmosis::poolmanager::v1beta1::SwapAmountInSplitRoute;
 use pyth_sdk_cw::PriceIdentifier;
 
-use crate::state::{Config, Metadata};
+use crate::state::{
+    CircuitBreaker, CommissionReceiver, Config, DepositPolicy, FeeDestination, FillOrder, Metadata,
+    MockPrice, Numeraire, Pauses, PendingCommission, PendingOwner, PriceBound, Role,
+    TimelockedChange,
+};
 
 #[cw_serde]
 pub struct InstantiateMsg {
     pub metadata: Option<Metadata>,
+    // Tokenfactory subdenom of the vault token, defaults to BVT
+    pub subdenom: Option<String>,
     // CL Assets with their corresponding pyth price feed
     pub asset0: VaultAsset,
     pub asset1: VaultAsset,
     // Minimum amount of tokens that can be deposited in a single tx
     pub min_asset0: Uint128,
     pub min_asset1: Uint128,
-    // Seconds after which a price quote is rejected and entries can't be processed
+    // Seconds after which a price quote is rejected and entries can't be processed, unless an asset sets its own
     pub price_expiry: u64,
+    // Quotes with a wider confidence interval relative to the price are rejected
+    pub max_confidence_ratio: Option<Decimal>,
+    // Side of the confidence interval used to price mints and burns. Defaults to the mid price
+    pub price_bound: Option<PriceBound>,
+    // What the vault is valued in, can't be changed later. Defaults to USD
+    pub numeraire: Option<Numeraire>,
     // Must be a CL pool
     pub pool_id: u64,
     // Minimum amount of tokens that can be redeemed in a single tx
     pub min_redemption: Option<Uint128>,
-    // USD cap: 1 * 10^(18+8) = 1 USD
+    // Cap in numeraire units: 1 * 10^(18+8) = 1 whole numeraire token, 1 USD by default. Named from
+    // when vaults were always valued in dollars, as are the other "dollar" fields and queries
     pub dollar_cap: Option<Uint128>,
+    // Default per-address cap on the value of vault tokens held, in the same numeraire units as the
+    // dollar cap. Deposits over it are refunded when processed
+    pub address_cap: Option<Uint128>,
     // Vault commission, as a percentage
     pub commission: Option<Decimal>,
-    // If not specified, receiver will be set to the owner
-    pub commission_receiver: Option<Addr>,
-    // Used to get the desired pyth contract address - defaults to mainnet
+    // Highest commission that can ever be set. Defaults to the initial commission
+    pub max_commission: Option<Decimal>,
+    // Seconds a commission increase must wait before being applied, at least a day. Lowering it waits out the current delay
+    pub commission_delay: u64,
+    // Seconds config, pool, operator and strategist changes must wait before being executed, at least a day
+    pub timelock_delay: u64,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    // Defaults to letting whitelisted addresses exceed the cap
+    pub deposit_policy: Option<DepositPolicy>,
+    // How pending mints are filled when they don't all fit under the dollar cap. Defaults to first come first served
+    pub fill_order: Option<FillOrder>,
+    // Refund what doesn't fit under the dollar cap instead of leaving it in queue. Defaults to false
+    pub refund_unfilled: Option<bool>,
+    // Seconds without operator activity before anyone can unlock the vault. Defaults to 14 days
+    pub update_interval: Option<u64>,
+    // Weighted commission receivers. If not specified, the owner receives all commissions
+    pub commission_receivers: Option<Vec<CommissionReceiver>>,
+    // Annualized management fee on total supply, as a percentage
+    pub management_fee: Option<Decimal>,
+    // Entry and exit fees, as a percentage
+    pub deposit_fee: Option<Decimal>,
+    pub withdraw_fee: Option<Decimal>,
+    // Defaults to the vault
+    pub fee_destination: Option<FeeDestination>,
+    // Pyth contract used by the vault
+    pub pyth_contract_address: Option<Addr>,
+    // Shortcut for the pyth contract address of known networks when none is given - defaults to mainnet
     pub env: Option<Environment>,
     // Vault operator address
     pub operator: Addr,
@@ -46,6 +86,49 @@ pub struct VaultAsset {
     pub price_identifier: PriceIdentifier,
     // Need to know decimals to convert from pyth price to asset price
     pub decimals: u32,
+    // Where the price comes from. Defaults to the pyth feed above
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oracle: Option<PriceSource>,
+    // Used when the oracle can't give a fresh quote
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<PriceSource>,
+    // Seconds after which a pyth quote for this asset is rejected. Defaults to the vault price expiry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price_expiry: Option<u64>,
+}
+
+#[cw_serde]
+pub enum PriceSource {
+    // Pyth feed quoted in dollars
+    Pyth {
+        price_identifier: PriceIdentifier,
+    },
+    // Osmosis TWAP against a quote denom, which is priced by its own source
+    Twap {
+        pool_id: u64,
+        quote_denom: String,
+        quote_decimals: u32,
+        quote_source: Box<PriceSource>,
+        // Seconds the average is taken over
+        window: u64,
+        kind: TwapKind,
+    },
+    // Dollars per whole token, for stablecoins
+    Fixed {
+        price: Decimal,
+    },
+    // Median of the quotes of several sources, failing or stale ones are skipped
+    Median {
+        sources: Vec<PriceSource>,
+        // Quotes needed for a median, at most the number of sources
+        min_quotes: u32,
+    },
+}
+
+#[cw_serde]
+pub enum TwapKind {
+    Arithmetic,
+    Geometric,
 }
 
 #[cw_serde]
@@ -56,7 +139,7 @@ pub enum ExecuteMsg {
     ManagePosition(PositionMsg),
     // Join/leave vault
     Deposit(DepositMsg),
-    // Dead man switch. Can be called to unlock the vault and allow manual redemptions after 14 days of operator inactivity
+    // Dead man switch. Can be called to unlock the vault and allow manual redemptions after the configured period of operator inactivity
     Unlock,
 }
 
@@ -66,24 +149,67 @@ pub enum VaultMsg {
     Modify(ModifyMsg),
     CompoundRewards(Vec<Swap>),
     CollectCommission,
+    // Mint the management fee accrued since the last accrual
+    AccrueFees,
     // Process entries and exits
     ProcessMints,
     ProcessBurns,
-    // Halt and Resume deposits and exits
-    Halt,
-    Resume,
+    // Same, pushing the Pyth price update VAAs first with the update fee sent along
+    ProcessMintsWithPriceUpdates { price_updates: Vec<Binary> },
+    ProcessBurnsWithPriceUpdates { price_updates: Vec<Binary> },
+    // Operator proof of life, delays the dead man switch
+    Heartbeat,
+    // Count the vault assets sent directly to the contract as part of the vault
+    AbsorbDonations,
+    // Pause the selected switches. Guardians can pause but only the owner can resume
+    Pause(Pauses),
+    Resume(Pauses),
+    // Set the prices served by the mock oracle. Test vaults only
+    SetMockPrices(Vec<MockPrice>),
 }
 
 #[cw_serde]
 pub enum ModifyMsg {
+    // Offer the ownership to a new address, which has to accept it before the optional expiry
+    TransferOwnership {
+        new_owner: Addr,
+        expiry: Option<u64>,
+    },
+    // Called by the pending owner
+    AcceptOwnership,
+    // Leave the vault without an owner. Owner only actions will no longer be possible
+    RenounceOwnership,
+    // Operator, Config and PoolId changes are queued for the timelock delay
     Operator(Addr),
+    GrantRole {
+        address: Addr,
+        role: Role,
+    },
+    RevokeRole {
+        address: Addr,
+        role: Role,
+    },
     Config(Box<Config>),
     PoolId(u64),
+    // Execute or cancel a queued change by id
+    ExecuteQueued(u64),
+    CancelQueued(u64),
+    // Decreases apply immediately, increases are queued until the commission delay has passed
     Commission(Decimal),
+    ApplyCommission,
+    CommissionReceivers(Vec<CommissionReceiver>),
     Whitelist {
         add: Option<Vec<Addr>>,
         remove: Option<Vec<Addr>>,
     },
+    // Override the default address cap for some addresses
+    AddressCaps {
+        set: Option<Vec<AddressCap>>,
+        remove: Option<Vec<Addr>>,
+    },
+    // Hex encoded merkle root of sha256(0x00 + address length as u32 BE + address + max_deposit as
+    // u128 BE) leaves, inner nodes hash 0x01 + the sorted pair. None lifts the gate
+    DepositMerkleRoot(Option<String>),
 }
 
 #[cw_serde]
@@ -116,6 +242,8 @@ pub enum PositionMsg {
 pub enum DepositMsg {
     Mint {
         min_out: Option<Uint128>,
+        // Required while deposits are gated by a merkle root
+        allocation: Option<Allocation>,
     },
     Burn {
         address: Option<Addr>,
@@ -123,6 +251,21 @@ pub enum DepositMsg {
     },
 }
 
+#[cw_serde]
+pub struct AddressCap {
+    pub address: Addr,
+    pub cap: Uint128,
+}
+
+#[cw_serde]
+pub struct Allocation {
+    // Max value the address can deposit in total, in numeraire units. 1 * 10^(18+8) = 1 whole
+    // numeraire token, 1 USD by default
+    pub max_deposit: Uint128,
+    // Hex encoded sibling hashes from the leaf up to the root
+    pub proof: Vec<String>,
+}
+
 #[cw_serde]
 pub struct Swap {
     pub routes: Vec<SwapAmountInSplitRoute>,
@@ -141,6 +284,15 @@ pub enum QueryMsg {
     AccountStatus(AccountQuery),
     #[returns(Vec<Coin>)]
     Rewards(RewardQuery),
+    // Vault assets sent directly to the contract and not yet absorbed
+    #[returns(Vec<Coin>)]
+    Donations,
+    // Cap that applies to an address, if any
+    #[returns(Option<Uint128>)]
+    AddressCap { address: Addr },
+    // Value deposited so far by an address in numeraire units, counted against its merkle allocation
+    #[returns(Uint128)]
+    DepositedDollars { address: Addr },
     #[returns(WhitelistResponse)]
     Whitelist {
         start_after: Option<Addr>,
@@ -148,6 +300,16 @@ pub enum QueryMsg {
     },
     #[returns(State)]
     VaultState(StateQuery),
+    #[returns(RolesResponse)]
+    Roles {
+        start_after: Option<Addr>,
+        limit: Option<u32>,
+    },
+    #[returns(Vec<QueuedChangeResponse>)]
+    QueuedChanges {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
 }
 
 #[cw_serde]
@@ -188,33 +350,86 @@ pub struct WhitelistResponse {
 }
 
 #[cw_serde]
+pub struct QueuedChangeResponse {
+    pub id: u64,
+    pub change: TimelockedChange,
+    pub eta: u64,
+}
+
+#[cw_serde]
+pub struct RolesResponse {
+    pub roles: Vec<RoleAssignment>,
+}
+
+#[cw_serde]
+pub struct RoleAssignment {
+    pub address: Addr,
+    pub roles: Vec<Role>,
+}
+
+#[cw_serde]
+#[allow(clippy::large_enum_variant)]
 pub enum State {
     Info {
         asset0: VaultAsset,
         asset1: VaultAsset,
         pool_id: u64,
-        owner: Addr,
+        owner: Option<Addr>,
+        pending_owner: Option<PendingOwner>,
         operator: Addr,
         commission_rate: Decimal,
+        max_commission: Decimal,
+        pending_commission: Option<PendingCommission>,
+        commission_receivers: Vec<CommissionReceiver>,
         config: Box<Config>,
     },
     Status {
         join_time: u64,
         last_update: u64,
+        // Seconds before anyone can unlock the vault
+        unlock_in: u64,
         uptime_locked: bool,
         cap_reached: bool,
-        halted: bool,
+        paused: Pauses,
         terminated: bool,
         supply: Uint128,
         denom: String,
     },
+    Nav {
+        numeraire: Numeraire,
+        // Value of the vault assets, in units of 10^-26 of the numeraire
+        total_value: Uint256,
+        // Numeraire units per vault token unit
+        vault_price: Decimal256,
+        supply: Uint128,
+    },
 }
 
 #[cw_serde]
 pub enum StateQuery {
     Info,
     Status,
+    // Net asset value in the numeraire
+    Nav,
 }
 
 #[cw_serde]
-pub struct MigrateMsg {}
+#[derive(Default)]
+pub struct MigrateMsg {
+    // Settings introduced since the stored version. Missing ones get the same defaults as on instantiation
+    pub max_commission: Option<Decimal>,
+    pub commission_delay: Option<u64>,
+    pub timelock_delay: Option<u64>,
+    pub update_interval: Option<u64>,
+    pub management_fee: Option<Decimal>,
+    pub deposit_fee: Option<Decimal>,
+    pub withdraw_fee: Option<Decimal>,
+    pub fee_destination: Option<FeeDestination>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    pub deposit_policy: Option<DepositPolicy>,
+    pub address_cap: Option<Uint128>,
+    pub fill_order: Option<FillOrder>,
+    pub refund_unfilled: Option<bool>,
+    pub max_confidence_ratio: Option<Decimal>,
+    pub price_bound: Option<PriceBound>,
+}
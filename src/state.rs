@@ -1,8 +1,11 @@
-use crate::msg::VaultAsset;
+use crate::msg::{PriceSource, VaultAsset};
 use cosmwasm_schema::cw_serde;
 use cosmwasm_std::Empty;
-use cosmwasm_std::{Addr, Coin, Decimal, Uint128};
+use cosmwasm_std::{
+    from_json, to_json_string, Addr, Coin, Decimal, Decimal256, StdError, StdResult, Uint128,
+};
 use cw_storage_plus::{Item, Map};
+use pyth_sdk_cw::PriceIdentifier;
 
 /// Top level storage key. Values must not conflict.
 /// Each key is only one byte long to ensure we use the smallest possible storage keys.
@@ -25,12 +28,31 @@ pub enum TopKey {
     AccountsPendingBurn = b'o',
     PositionOpen = b'p',
     CapReached = b'q',
-    Halted = b'r',
+    Paused = b'r',
     Terminated = b's',
+    LastFeeAccrual = b't',
+    CommissionReceivers = b'u',
+    MaxCommission = b'v',
+    PendingCommission = b'w',
+    PendingOwner = b'x',
+    Roles = b'y',
+    TimelockQueue = b'z',
+    TimelockNextId = b'A',
+    LastVaultPrice = b'B',
+    DepositMerkleRoot = b'C',
+    DepositedDollars = b'D',
+    AddressCaps = b'E',
+    MintQueuePositions = b'F',
+    NextMintQueuePosition = b'G',
+    LockedShares = b'H',
+    TrackedBalances = b'I',
+    BalancesSnapshot = b'J',
+    MockPrices = b'K',
+    PendingAllocations = b'L',
 }
 
 impl TopKey {
-    const fn as_str(&self) -> &str {
+    pub(crate) const fn as_str(&self) -> &str {
         let array_ref = unsafe { std::mem::transmute::<_, &[u8; 1]>(self) };
         match core::str::from_utf8(array_ref) {
             Ok(a) => a,
@@ -41,17 +63,40 @@ impl TopKey {
 
 // Contract Owner
 pub const OWNER: Item<Addr> = Item::new(TopKey::Owner.as_str());
+// Address an ownership transfer has been offered to
+pub const PENDING_OWNER: Item<PendingOwner> = Item::new(TopKey::PendingOwner.as_str());
 // Contract Operator
 pub const OPERATOR: Item<Addr> = Item::new(TopKey::Operator.as_str());
+// Roles granted by the owner. The operator implicitly holds all of them
+pub const ROLES: Map<Addr, Vec<Role>> = Map::new(TopKey::Roles.as_str());
+// Sensitive changes waiting for the timelock delay to pass, by id
+pub const TIMELOCK_QUEUE: Map<u64, QueuedChange> = Map::new(TopKey::TimelockQueue.as_str());
+pub const TIMELOCK_NEXT_ID: Item<u64> = Item::new(TopKey::TimelockNextId.as_str());
 // Contract Config
 pub const CONFIG: Item<Config> = Item::new(TopKey::Config.as_str());
 // CL pool id
 pub const POOL_ID: Item<u64> = Item::new(TopKey::PoolId.as_str());
 // Assets that can be deposited in the vault
 pub const VAULT_ASSETS: Item<(VaultAsset, VaultAsset)> = Item::new(TopKey::VaultAssets.as_str());
+// Merkle root of (address, max deposit in numeraire units) leaves gating deposits, hex encoded
+pub const DEPOSIT_MERKLE_ROOT: Item<String> = Item::new(TopKey::DepositMerkleRoot.as_str());
+// Value deposited so far by each address in numeraire units, counted against its allocation
+pub const DEPOSITED_DOLLARS: Map<Addr, Uint128> = Map::new(TopKey::DepositedDollars.as_str());
+// Part of the deposited value that is still waiting to mint, given back if it gets refunded
+pub const PENDING_ALLOCATIONS: Map<Addr, Uint128> = Map::new(TopKey::PendingAllocations.as_str());
+// Per-address overrides of the default address cap, in numeraire units
+pub const ADDRESS_CAPS: Map<Addr, Uint128> = Map::new(TopKey::AddressCaps.as_str());
 // Tokenfactory denom for the vault token
 // rate to charge for the vault
 pub const COMMISSION_RATE: Item<Decimal> = Item::new(TopKey::CommissionRate.as_str());
+// highest commission rate that can ever be set, fixed at instantiation
+pub const MAX_COMMISSION: Item<Decimal> = Item::new(TopKey::MaxCommission.as_str());
+// commission increase waiting for its delay to pass
+pub const PENDING_COMMISSION: Item<PendingCommission> =
+    Item::new(TopKey::PendingCommission.as_str());
+// who the commissions are paid to and in which proportion
+pub const COMMISSION_RECEIVERS: Item<Vec<CommissionReceiver>> =
+    Item::new(TopKey::CommissionReceivers.as_str());
 // collected commissions
 pub const COMMISSION_REWARDS: Item<Vec<Coin>> = Item::new(TopKey::CommissionRewards.as_str());
 // collected rewards that are not asset0 or asset1
@@ -61,13 +106,25 @@ pub const WHITELISTED_DEPOSITORS: Map<Addr, Empty> =
 pub const VAULT_DENOM: Item<String> = Item::new(TopKey::VaultDenom.as_str());
 // Total supply of vault tokens
 pub const SUPPLY: Item<Uint128> = Item::new(TopKey::Supply.as_str());
+// Part of the supply held by the contract itself since instantiation, never burned
+pub const LOCKED_SHARES: Item<Uint128> = Item::new(TopKey::LockedShares.as_str());
 // Last time exits and joins were processed
 pub const LAST_UPDATE: Item<u64> = Item::new(TopKey::LastUpdate.as_str());
+// Vault assets held by the contract by its own accounting, pending mints and commissions included.
+// Anything held above it was sent directly to the contract
+pub const TRACKED_BALANCES: Item<Vec<Coin>> = Item::new(TopKey::TrackedBalances.as_str());
+// Untracked balances when messages with unknown outcomes were sent, kept out of the resync in their reply
+pub const BALANCES_SNAPSHOT: Item<Vec<Coin>> = Item::new(TopKey::BalancesSnapshot.as_str());
+// Prices served by the mock oracle of test vaults, keyed by hex price identifier
+pub const MOCK_PRICES: Map<String, MockPrice> = Map::new(TopKey::MockPrices.as_str());
 // Assets waiting to join the vault
 pub const ASSETS_PENDING_MINT: Item<Vec<Coin>> = Item::new(TopKey::AssetPendingMint.as_str());
 // Accounts pending activation and how much for each one
 pub const ACCOUNTS_PENDING_MINT: Map<Addr, (Vec<Coin>, Uint128)> =
     Map::new(TopKey::AccountsPendingMint.as_str());
+// Place in line of each account pending activation, in deposit order
+pub const MINT_QUEUE_POSITIONS: Map<Addr, u64> = Map::new(TopKey::MintQueuePositions.as_str());
+pub const NEXT_MINT_QUEUE_POSITION: Item<u64> = Item::new(TopKey::NextMintQueuePosition.as_str());
 // Addresses pending to leave the vault
 pub const ACCOUNTS_PENDING_BURN: Map<Addr, Uint128> =
     Map::new(TopKey::AccountsPendingBurn.as_str());
@@ -75,10 +132,14 @@ pub const ACCOUNTS_PENDING_BURN: Map<Addr, Uint128> =
 pub const POSITION_OPEN: Item<bool> = Item::new(TopKey::PositionOpen.as_str());
 // Flag to indicate if the vault cap has been reached and no more people can join (they can leave though)
 pub const CAP_REACHED: Item<bool> = Item::new(TopKey::CapReached.as_str());
-// Flag to halt joins and exits (in case of some emergency)
-pub const HALTED: Item<bool> = Item::new(TopKey::Halted.as_str());
+// Switches to pause parts of the vault (in case of some emergency)
+pub const PAUSED: Item<Pauses> = Item::new(TopKey::Paused.as_str());
 // Flag to indicate that the vault has been terminated by owner
 pub const TERMINATED: Item<bool> = Item::new(TopKey::Terminated.as_str());
+// Vault price of the last processed mints or burns, watched by the circuit breaker
+pub const LAST_VAULT_PRICE: Item<PriceSnapshot> = Item::new(TopKey::LastVaultPrice.as_str());
+// Last time the management fee was accrued
+pub const LAST_FEE_ACCRUAL: Item<u64> = Item::new(TopKey::LastFeeAccrual.as_str());
 
 #[cw_serde]
 pub struct Config {
@@ -86,10 +147,259 @@ pub struct Config {
     pub min_asset0: Uint128,
     pub min_asset1: Uint128,
     pub min_redemption: Option<Uint128>,
+    // In numeraire units, the name predates the numeraire
     pub dollar_cap: Option<Uint128>,
+    // Default max value in numeraire units of vault tokens a single address can hold through deposits
+    pub address_cap: Option<Uint128>,
     pub pyth_contract_address: Addr,
     pub price_expiry: u64,
-    pub commission_receiver: Addr,
+    // Highest confidence interval to price ratio accepted from the oracle
+    pub max_confidence_ratio: Option<Decimal>,
+    // Which side of the confidence interval mints and burns are priced at
+    pub price_bound: PriceBound,
+    // What the vault is valued in. Caps, fees and vault prices are expressed in it
+    pub numeraire: Numeraire,
+    // Annualized fee on total supply, minted as vault tokens to the commission receivers
+    pub management_fee: Option<Decimal>,
+    // Fee withheld from deposits when they are processed
+    pub deposit_fee: Option<Decimal>,
+    // Fee withheld from redemptions when they are processed
+    pub withdraw_fee: Option<Decimal>,
+    // Where deposit and withdraw fees go, defaults to the vault
+    pub fee_destination: Option<FeeDestination>,
+    // Seconds a commission increase has to wait before it can be applied
+    pub commission_delay: u64,
+    // Seconds config, pool, operator and strategist changes have to wait before they can be executed
+    pub timelock_delay: u64,
+    // Seconds without operator activity after which anyone can unlock the vault
+    pub update_interval: u64,
+    // Pauses mints and burns when the vault price moves too much between processings
+    pub circuit_breaker: Option<CircuitBreaker>,
+    // Who can deposit, and what being whitelisted allows
+    pub deposit_policy: DepositPolicy,
+    // How the headroom under the dollar cap is shared between pending mints
+    pub fill_order: FillOrder,
+    // Whether the part of a mint that doesn't fit under the dollar cap is refunded or left in queue
+    pub refund_unfilled: bool,
+}
+
+// Lists every config field. Destructuring the config makes a missing field fail to compile
+macro_rules! config_changes {
+    ($($field:ident),* $(,)?) => {
+        impl Config {
+            // The fields of the new config that differ from this one
+            pub fn changes_to(&self, new_config: &Config) -> StdResult<Vec<ConfigChange>> {
+                let Config { $($field: _),* } = new_config;
+
+                let mut changes = vec![];
+                $(
+                    if self.$field != new_config.$field {
+                        changes.push(ConfigChange {
+                            field: stringify!($field).to_string(),
+                            value: to_json_string(&new_config.$field)?,
+                        });
+                    }
+                )*
+                Ok(changes)
+            }
+
+            pub fn apply_changes(&mut self, changes: &[ConfigChange]) -> StdResult<()> {
+                for change in changes {
+                    match change.field.as_str() {
+                        $(stringify!($field) => self.$field = from_json(&change.value)?,)*
+                        field => {
+                            return Err(StdError::generic_err(format!(
+                                "Unknown config field {field}"
+                            )))
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+config_changes!(
+    metadata,
+    min_asset0,
+    min_asset1,
+    min_redemption,
+    dollar_cap,
+    address_cap,
+    pyth_contract_address,
+    price_expiry,
+    max_confidence_ratio,
+    price_bound,
+    numeraire,
+    management_fee,
+    deposit_fee,
+    withdraw_fee,
+    fee_destination,
+    commission_delay,
+    timelock_delay,
+    update_interval,
+    circuit_breaker,
+    deposit_policy,
+    fill_order,
+    refund_unfilled,
+);
+
+// A single config field to change, so that queued changes don't revert fields changed since
+#[cw_serde]
+pub struct ConfigChange {
+    pub field: String,
+    // JSON encoded new value
+    pub value: String,
+}
+
+// Caps, allocations and prices are in units of 10^-26 of the numeraire, as they were for dollars.
+// Fields and queries named after dollars hold numeraire units
+#[cw_serde]
+pub enum Numeraire {
+    Usd,
+    Asset0,
+    Asset1,
+    // Denom outside the vault, priced in dollars by its own source
+    External {
+        denom: String,
+        decimals: u32,
+        source: PriceSource,
+    },
+}
+
+#[cw_serde]
+pub enum PriceBound {
+    // The oracle price as is
+    Mid,
+    // Mints at price minus confidence, burns at price plus confidence
+    LowerForMints,
+    // Mints at price plus confidence, burns at price minus confidence
+    UpperForMints,
+}
+
+#[cw_serde]
+pub enum FillOrder {
+    // In deposit order, until the cap is reached
+    FirstCome,
+    // Every pending mint gets the same share of its deposit filled
+    ProRata,
+}
+
+#[cw_serde]
+pub enum DepositPolicy {
+    // Anyone can deposit, the cap applies to everyone
+    Open,
+    // Only whitelisted addresses can deposit, the cap applies to everyone
+    WhitelistOnly,
+    // Anyone can deposit, whitelisted addresses can exceed the cap
+    CapBypassOnly,
+}
+
+#[cw_serde]
+pub struct CircuitBreaker {
+    // Max relative move of the vault price allowed within the window
+    pub threshold: Decimal,
+    // Seconds since the last processed price during which the threshold applies
+    pub window: u64,
+}
+
+// Raw feed values, as a pyth quote would have them
+#[cw_serde]
+pub struct MockPrice {
+    pub price_identifier: PriceIdentifier,
+    pub price: Uint128,
+    pub conf: Uint128,
+    pub expo: i32,
+}
+
+#[cw_serde]
+pub struct PriceSnapshot {
+    // Numeraire units per vault token unit
+    pub price: Decimal256,
+    pub time: u64,
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub struct Pauses {
+    // Deposits for mint and mint processing
+    pub mints: bool,
+    // Deposits for burn and burn processing
+    pub burns: bool,
+    // Creating and withdrawing positions
+    pub positions: bool,
+    pub compounding: bool,
+}
+
+impl Pauses {
+    pub fn all() -> Self {
+        Self {
+            mints: true,
+            burns: true,
+            positions: true,
+            compounding: true,
+        }
+    }
+}
+
+#[cw_serde]
+pub enum TimelockedChange {
+    Operator(Addr),
+    Config(Vec<ConfigChange>),
+    PoolId(u64),
+    // Roles with operator powers
+    GrantRole { address: Addr, role: Role },
+}
+
+#[cw_serde]
+pub struct QueuedChange {
+    pub change: TimelockedChange,
+    // Time after which the change can be executed
+    pub eta: u64,
+}
+
+#[cw_serde]
+pub enum Role {
+    // Manages the CL position and compounds rewards
+    Strategist,
+    // Processes the mint and burn queues
+    Processor,
+    // Can pause the vault, but not resume it
+    Guardian,
+    // Adds and removes whitelisted depositors
+    WhitelistManager,
+    // Collects commissions and accrues fees
+    FeeCollector,
+}
+
+#[cw_serde]
+pub struct PendingOwner {
+    pub address: Addr,
+    // Time after which the transfer can no longer be accepted
+    pub expiry: Option<u64>,
+}
+
+#[cw_serde]
+pub struct PendingCommission {
+    pub rate: Decimal,
+    // Time after which the new rate can be applied
+    pub eta: u64,
+}
+
+#[cw_serde]
+pub enum FeeDestination {
+    // Fees stay in the vault, accruing to the remaining holders
+    Vault,
+    // Fees are added to the commission rewards
+    Commission,
+}
+
+#[cw_serde]
+pub struct CommissionReceiver {
+    pub address: Addr,
+    // Share of the commissions paid to this address. All weights must add up to 100%
+    pub weight: Decimal,
 }
 
 #[cw_serde]
@@ -97,4 +407,8 @@ pub struct Metadata {
     pub name: String,
     pub description: Option<String>,
     pub image: Option<String>,
+    // Ticker shown by wallets, defaults to the subdenom
+    pub symbol: Option<String>,
+    // Decimals of the displayed unit, defaults to 18
+    pub exponent: Option<u32>,
 }
@@ -1,24 +1,40 @@
+use crate::contract::DEAD_SHARES;
 use crate::msg::{
-    AccountQuery, AccountQueryParams, AccountResponse, DepositMsg, Environment,
+    AccountQuery, AccountQueryParams, AccountResponse, AddressCap, Allocation, DepositMsg,
+    DepositQuery, Environment,
+    ExecuteMsg::Unlock,
     ExecuteMsg::{Deposit, ManagePosition, ManageVault},
-    InstantiateMsg,
+    InstantiateMsg, MigrateMsg, ModifyMsg,
     PositionMsg::{CreatePosition, WithdrawPosition},
-    QueryMsg::{AccountStatus, LockedAssets},
-    VaultAsset, VaultMsg,
+    PriceSource,
+    QueryMsg::{
+        AccountStatus, AddressCap as AddressCapQuery, DepositedDollars, Donations, EstimateDeposit,
+        LockedAssets, QueuedChanges, Rewards, Roles, VaultState,
+    },
+    QueuedChangeResponse, RewardQuery, RolesResponse, State, StateQuery, VaultAsset, VaultMsg,
+};
+use crate::state::{
+    CircuitBreaker, CommissionReceiver, Config, DepositPolicy, FeeDestination, FillOrder, Metadata,
+    MockPrice, Numeraire, Pauses, PriceBound, Role, TimelockedChange,
 };
 use cosmos_sdk_proto::cosmos::params::v1beta1::ParameterChangeProposal;
-use cosmwasm_std::{coin, Addr, Coin, Coins, Decimal, Uint128};
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{coin, to_json_vec, Addr, Binary, Coin, Coins, Decimal, Uint128, Uint256};
 use osmosis_std::types::{
-    cosmos::bank::v1beta1::{MsgSend, QueryTotalSupplyRequest},
+    cosmos::bank::v1beta1::{
+        MsgSend, QueryDenomMetadataRequest, QueryDenomMetadataResponse, QueryTotalSupplyRequest,
+    },
+    cosmwasm::wasm::v1::{MsgMigrateContract, MsgMigrateContractResponse},
     osmosis::concentratedliquidity::v1beta1::{MsgCreatePosition, UserPositionsRequest},
 };
 use osmosis_test_tube::osmosis_std::types::cosmos::bank::v1beta1::QueryBalanceRequest;
 use osmosis_test_tube::{
     osmosis_std::types::osmosis::concentratedliquidity::poolmodel::concentrated::v1beta1::MsgCreateConcentratedPool,
     Account, Bank, ConcentratedLiquidity, FeeSetting, GovWithAppAccess, Module, OsmosisTestApp,
-    SigningAccount, Wasm,
+    Runner, SigningAccount, Wasm,
 };
 use pyth_sdk_cw::PriceIdentifier;
+use sha2::{Digest, Sha256};
 use std::ops::Div;
 #[cfg(test)]
 use std::ops::Mul;
@@ -36,6 +52,8 @@ const PRECISION: (Decimal, Decimal) = (
     Decimal::new(Uint128::new(1000000)),
 );
 
+const TIMELOCK_DELAY: u64 = 86400;
+
 const FEE_AMOUNT: u128 = 2500;
 const TOTAL_FEES: u128 = FEE_AMOUNT * 20 * JOINS.len() as u128;
 
@@ -92,6 +110,11 @@ fn get_modules(test_env: &'_ TestEnv) -> Modules<'_> {
 }
 
 fn setup_contract(asset1: VaultAsset) -> TestEnv {
+    setup_contract_with(asset1, |_| {})
+}
+
+// same as setup_contract, but allows the instantiate msg to be tweaked before it is sent
+fn setup_contract_with(asset1: VaultAsset, customize: impl FnOnce(&mut InstantiateMsg)) -> TestEnv {
     let app = OsmosisTestApp::new();
 
     let admin = app
@@ -204,32 +227,56 @@ fn setup_contract(asset1: VaultAsset) -> TestEnv {
         .data
         .code_id;
 
+    let mut instantiate_msg = InstantiateMsg {
+        metadata: None,
+        subdenom: None,
+        pool_id: 1,
+        price_expiry: 60,
+        max_confidence_ratio: None,
+        price_bound: None,
+        numeraire: None,
+        min_asset0: 10000_u64.into(),
+        min_asset1: 10000_u64.into(),
+        asset0: VaultAsset {
+            denom: "uosmo".to_string(),
+            price_identifier: PriceIdentifier::from_hex(
+                "5867f5683c757393a0670ef0f701490950fe93fdb006d181c8265a831ac0c5c6",
+            )
+            .unwrap(),
+            decimals: 6,
+            oracle: None,
+            fallback: None,
+            price_expiry: None,
+        },
+        asset1: asset1.clone(),
+        min_redemption: None,
+        dollar_cap: None,
+        address_cap: None,
+        commission: Some(Decimal::from_ratio(1_u128, 100_u128)),
+        max_commission: Some(Decimal::percent(20)),
+        commission_delay: 86400,
+        timelock_delay: TIMELOCK_DELAY,
+        circuit_breaker: None,
+        deposit_policy: None,
+        fill_order: None,
+        refund_unfilled: None,
+        update_interval: None,
+        commission_receivers: None,
+        pyth_contract_address: None,
+        env: Some(Environment::Testtube),
+        operator: Addr::unchecked(test_env.admin.address()),
+        management_fee: None,
+        deposit_fee: None,
+        withdraw_fee: None,
+        fee_destination: None,
+    };
+    customize(&mut instantiate_msg);
+
     let contract_addr = modules
         .wasm
         .instantiate(
             code_id,
-            &InstantiateMsg {
-                metadata: None,
-                pool_id: 1,
-                price_expiry: 60,
-                min_asset0: 10000_u64.into(),
-                min_asset1: 10000_u64.into(),
-                asset0: VaultAsset {
-                    denom: "uosmo".to_string(),
-                    price_identifier: PriceIdentifier::from_hex(
-                        "5867f5683c757393a0670ef0f701490950fe93fdb006d181c8265a831ac0c5c6",
-                    )
-                    .unwrap(),
-                    decimals: 6,
-                },
-                asset1: asset1.clone(),
-                min_redemption: None,
-                dollar_cap: None,
-                commission: Some(Decimal::from_ratio(1_u128, 100_u128)),
-                commission_receiver: Some(Addr::unchecked(test_env.admin.address())),
-                env: Some(Environment::Testtube),
-                operator: Addr::unchecked(test_env.admin.address()),
-            },
+            &instantiate_msg,
             Some(&test_env.admin.address()),
             Some("bv"),
             &[coin(100_000_000, "uosmo")],
@@ -251,10 +298,58 @@ fn setup_contract(asset1: VaultAsset) -> TestEnv {
         )
         .unwrap();
 
+    // sent directly, so it only counts once absorbed
+    modules
+        .wasm
+        .execute(
+            &contract_addr,
+            &ManageVault(VaultMsg::AbsorbDonations),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
     test_env.contract_addr = contract_addr;
     test_env
 }
 
+// Queues a timelocked change and executes it once the delay has passed
+fn execute_timelocked(test_env: &TestEnv, modules: &Modules, msg: ModifyMsg) {
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(msg)),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    let queued: Vec<QueuedChangeResponse> = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &QueuedChanges {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+
+    test_env.app.increase_time(TIMELOCK_DELAY);
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::ExecuteQueued(
+                queued.last().unwrap().id,
+            ))),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+}
+
 fn get_asset(denom: &str) -> VaultAsset {
     match denom {
         "uatom" => VaultAsset {
@@ -264,6 +359,9 @@ fn get_asset(denom: &str) -> VaultAsset {
             )
             .unwrap(),
             decimals: 6,
+            oracle: None,
+            fallback: None,
+            price_expiry: None,
         },
         "wei" => VaultAsset {
             denom: "wei".to_string(),
@@ -272,6 +370,9 @@ fn get_asset(denom: &str) -> VaultAsset {
             )
             .unwrap(),
             decimals: 18,
+            oracle: None,
+            fallback: None,
+            price_expiry: None,
         },
         _ => panic!("invalid denom"),
     }
@@ -326,7 +427,10 @@ fn execute_joins(
             .wasm
             .execute(
                 &test_env.contract_addr,
-                &Deposit(DepositMsg::Mint { min_out: None }),
+                &Deposit(DepositMsg::Mint {
+                    min_out: None,
+                    allocation: None,
+                }),
                 &[coin(join_amounts.0[i] * exp, join_denom)],
                 user,
             )
@@ -675,7 +779,10 @@ fn test_multiple_recalculations() {
         .wasm
         .execute(
             &test_env.contract_addr,
-            &Deposit(DepositMsg::Mint { min_out: None }),
+            &Deposit(DepositMsg::Mint {
+                min_out: None,
+                allocation: None,
+            }),
             &[coin(53_000_000, "uatom"), coin(500_000_000, "uosmo")],
             &user,
         )
@@ -937,3 +1044,2123 @@ fn test_queries() {
         )
         .unwrap();
 }
+
+#[test]
+fn test_management_fee() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.management_fee = Some(Decimal::percent(10));
+    });
+    let modules = get_modules(&test_env);
+    let vault_denom = format!("factory/{}/BVT", test_env.contract_addr);
+
+    let initial_balance = modules
+        .bank
+        .query_balance(&QueryBalanceRequest {
+            address: test_env.admin.address(),
+            denom: vault_denom.clone(),
+        })
+        .unwrap()
+        .balance
+        .unwrap()
+        .amount
+        .parse::<u128>()
+        .unwrap();
+
+    test_env.app.increase_time(86400 * 365);
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::AccrueFees),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    let supply = modules
+        .bank
+        .query_total_supply(&QueryTotalSupplyRequest { pagination: None })
+        .unwrap()
+        .supply
+        .iter()
+        .find(|c| c.denom == vault_denom)
+        .unwrap()
+        .amount
+        .parse::<u128>()
+        .unwrap();
+
+    let final_balance = modules
+        .bank
+        .query_balance(&QueryBalanceRequest {
+            address: test_env.admin.address(),
+            denom: vault_denom,
+        })
+        .unwrap()
+        .balance
+        .unwrap()
+        .amount
+        .parse::<u128>()
+        .unwrap();
+
+    // a year at 10% should mint (slightly more than, due to block time) 10% of the supply
+    let fee = final_balance - initial_balance;
+    assert!(fee >= initial_balance / 10);
+    assert!(fee < initial_balance / 10 + initial_balance / 100_000);
+    assert_eq!(supply, initial_balance + fee + DEAD_SHARES);
+}
+
+#[test]
+fn test_donations() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+
+    let locked_assets = || -> Vec<Coin> {
+        modules
+            .wasm
+            .query(&test_env.contract_addr, &LockedAssets {})
+            .unwrap()
+    };
+    let donations = || -> Vec<Coin> {
+        modules
+            .wasm
+            .query(&test_env.contract_addr, &Donations {})
+            .unwrap()
+    };
+
+    let assets_before = locked_assets();
+
+    modules
+        .bank
+        .send(
+            MsgSend {
+                from_address: test_env.users[0].address(),
+                to_address: test_env.contract_addr.clone(),
+                amount: vec![coin(50_000_000, "uosmo").into()],
+            },
+            &test_env.users[0],
+        )
+        .unwrap();
+
+    // sending funds to the vault doesn't move its value
+    assert_eq!(locked_assets(), assets_before);
+    assert_eq!(
+        donations(),
+        vec![coin(50_000_000, "uosmo"), coin(0, "uatom")]
+    );
+
+    // only the operator can fold them in
+    assert!(modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::AbsorbDonations),
+            &[],
+            &test_env.users[0],
+        )
+        .is_err());
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::AbsorbDonations),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    assert_eq!(
+        locked_assets()[0].amount,
+        assets_before[0].amount + Uint128::new(50_000_000)
+    );
+    assert_eq!(donations(), vec![coin(0, "uosmo"), coin(0, "uatom")]);
+}
+
+#[test]
+fn test_large_amounts() {
+    let test_env = setup_contract(get_asset("wei"));
+    let modules = get_modules(&test_env);
+    let vault_denom = format!("factory/{}/BVT", test_env.contract_addr);
+
+    // a trillion ETH is worth more dollars than fit in a Uint128, 1 * 10^(18+8) = 1 USD
+    let deposit = 10_u128.pow(30);
+    let whale = test_env
+        .app
+        .init_account(&[
+            Coin::new(deposit, "wei"),
+            Coin::new(1_000_000_000_000, "uosmo"),
+        ])
+        .unwrap();
+
+    let balance = |denom: &str| {
+        modules
+            .bank
+            .query_balance(&QueryBalanceRequest {
+                address: whale.address(),
+                denom: denom.to_string(),
+            })
+            .unwrap()
+            .balance
+            .map(|c| c.amount.parse::<u128>().unwrap())
+            .unwrap_or_default()
+    };
+
+    let execute = |msg: VaultMsg| {
+        modules.wasm.execute(
+            &test_env.contract_addr,
+            &ManageVault(msg),
+            &[],
+            &test_env.admin,
+        )
+    };
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &Deposit(DepositMsg::Mint {
+                min_out: None,
+                allocation: None,
+            }),
+            &[coin(deposit, "wei")],
+            &whale,
+        )
+        .unwrap();
+    execute(VaultMsg::ProcessMints).unwrap();
+
+    let minted = balance(&vault_denom);
+    assert!(minted > 0);
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &Deposit(DepositMsg::Burn {
+                address: None,
+                amount: None,
+            }),
+            &[coin(minted, &vault_denom)],
+            &whale,
+        )
+        .unwrap();
+    execute(VaultMsg::ProcessBurns).unwrap();
+
+    // the whale owns nearly all the vault, so it gets back about what it put in
+    let returned = balance("wei");
+    assert!(returned > deposit - deposit / 1_000_000);
+}
+
+#[test]
+fn test_initial_mint() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+    let vault_denom = format!("factory/{}/BVT", test_env.contract_addr);
+
+    let balance = |address: String| {
+        modules
+            .bank
+            .query_balance(&QueryBalanceRequest {
+                address,
+                denom: vault_denom.clone(),
+            })
+            .unwrap()
+            .balance
+            .unwrap()
+            .amount
+            .parse::<u128>()
+            .unwrap()
+    };
+
+    // the 100 OSMO seed is worth $164.243925, minted at 1 USD per 10^18 vault tokens
+    let initial_mint = 164_243_925 * 10_u128.pow(12);
+    assert_eq!(
+        balance(test_env.admin.address()),
+        initial_mint - DEAD_SHARES
+    );
+    assert_eq!(balance(test_env.contract_addr.clone()), DEAD_SHARES);
+
+    let vault_state: State = modules
+        .wasm
+        .query(&test_env.contract_addr, &VaultState(StateQuery::Status))
+        .unwrap();
+    match vault_state {
+        State::Status { supply, .. } => assert_eq!(supply, Uint128::new(initial_mint)),
+        _ => panic!("unexpected state"),
+    }
+}
+
+#[test]
+fn test_confidence_bounds() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.max_confidence_ratio = Some(Decimal::percent(1));
+        msg.price_bound = Some(PriceBound::LowerForMints);
+    });
+    let modules = get_modules(&test_env);
+
+    // mock quotes have no confidence interval, so both bounds match the mid price
+    let vault_state: State = modules
+        .wasm
+        .query(&test_env.contract_addr, &VaultState(StateQuery::Status))
+        .unwrap();
+    match vault_state {
+        State::Status { supply, .. } => {
+            assert_eq!(supply, Uint128::new(164_243_925 * 10_u128.pow(12)))
+        }
+        _ => panic!("unexpected state"),
+    }
+
+    execute_joins(
+        &test_env,
+        &modules,
+        JOINS[0],
+        &"uosmo".to_string(),
+        1_000_000,
+    );
+    execute_leaves(&test_env, &modules);
+
+    let estimate_mint = || {
+        modules.wasm.query::<_, Vec<Coin>>(
+            &test_env.contract_addr,
+            &EstimateDeposit(DepositQuery::Mint(vec![coin(1_000_000, "uatom")])),
+        )
+    };
+    let set_atom_conf = |conf: u128| {
+        modules
+            .wasm
+            .execute(
+                &test_env.contract_addr,
+                &ManageVault(VaultMsg::SetMockPrices(vec![MockPrice {
+                    price_identifier: get_asset("uatom").price_identifier,
+                    price: Uint128::new(1_031_081_328),
+                    conf: Uint128::new(conf),
+                    expo: -8,
+                }])),
+                &[],
+                &test_env.admin,
+            )
+            .unwrap();
+    };
+    let mid = estimate_mint().unwrap()[0].amount;
+
+    // ~0.5% wide, ATOM is priced at its lower bound for mints and ATOM joins get fewer tokens
+    set_atom_conf(5_155_406);
+    let lower = estimate_mint().unwrap()[0].amount;
+    assert!(lower < mid);
+    assert!(lower > mid * Decimal::percent(99));
+
+    // ~2% wide, over the max ratio
+    set_atom_conf(20_621_626);
+    let err = estimate_mint().unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("confidence interval for uatom is too wide"));
+}
+
+#[test]
+fn test_price_sources() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.asset0.oracle = Some(PriceSource::Median {
+            sources: vec![
+                PriceSource::Pyth {
+                    price_identifier: msg.asset0.price_identifier,
+                },
+                PriceSource::Fixed {
+                    price: Decimal::from_ratio(164_243_925_u128, 100_000_000_u128),
+                },
+                PriceSource::Fixed {
+                    price: Decimal::percent(10_000),
+                },
+                // no mock price, so this source fails and is left out
+                PriceSource::Pyth {
+                    price_identifier: PriceIdentifier::from_hex(
+                        "0000000000000000000000000000000000000000000000000000000000000001",
+                    )
+                    .unwrap(),
+                },
+            ],
+            min_quotes: 3,
+        });
+        msg.asset1.oracle = Some(PriceSource::Fixed {
+            price: Decimal::from_ratio(1_031_081_328_u128, 100_000_000_u128),
+        });
+    });
+    let modules = get_modules(&test_env);
+
+    // the median and the fixed prices match the mock pyth quotes
+    let vault_state: State = modules
+        .wasm
+        .query(&test_env.contract_addr, &VaultState(StateQuery::Status))
+        .unwrap();
+    match vault_state {
+        State::Status { supply, .. } => {
+            assert_eq!(supply, Uint128::new(164_243_925 * 10_u128.pow(12)))
+        }
+        _ => panic!("unexpected state"),
+    }
+
+    execute_joins(
+        &test_env,
+        &modules,
+        JOINS[0],
+        &"uatom".to_string(),
+        1_000_000,
+    );
+    execute_leaves(&test_env, &modules);
+}
+
+#[test]
+fn test_mock_prices() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+
+    let estimate_mint = || -> Uint128 {
+        let estimate: Vec<Coin> = modules
+            .wasm
+            .query(
+                &test_env.contract_addr,
+                &EstimateDeposit(DepositQuery::Mint(vec![coin(1_000_000, "uatom")])),
+            )
+            .unwrap();
+        estimate[0].amount
+    };
+    let before = estimate_mint();
+
+    let atom_price = MockPrice {
+        price_identifier: get_asset("uatom").price_identifier,
+        price: Uint128::new(2_062_162_656),
+        conf: Uint128::zero(),
+        expo: -8,
+    };
+
+    // only the owner moves the market
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::SetMockPrices(vec![atom_price.clone()])),
+            &[],
+            &test_env.users[0],
+        )
+        .unwrap_err();
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::SetMockPrices(vec![atom_price])),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    // ATOM is a small part of the vault, so ATOM joins get close to twice the tokens
+    let after = estimate_mint();
+    assert!(after > before * Uint128::new(19) / Uint128::new(10));
+    assert!(after < before * Uint128::new(2));
+}
+
+#[test]
+fn test_process_with_price_updates() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+    let vault_denom = format!("factory/{}/BVT", test_env.contract_addr);
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &Deposit(DepositMsg::Mint {
+                min_out: None,
+                allocation: None,
+            }),
+            &[coin(1_000_000, "uatom")],
+            &test_env.users[0],
+        )
+        .unwrap();
+
+    // test vaults have no pyth contract, so no fee is paid and funds would be left behind
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::ProcessMintsWithPriceUpdates {
+                price_updates: vec![Binary::from(b"update".to_vec())],
+            }),
+            &[coin(1, "uosmo")],
+            &test_env.admin,
+        )
+        .unwrap_err();
+
+    // the updates are skipped and entries processed right away
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::ProcessMintsWithPriceUpdates {
+                price_updates: vec![Binary::from(b"update".to_vec())],
+            }),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    let balance = modules
+        .bank
+        .query_balance(&QueryBalanceRequest {
+            address: test_env.users[0].address(),
+            denom: vault_denom,
+        })
+        .unwrap()
+        .balance
+        .unwrap()
+        .amount
+        .parse::<u128>()
+        .unwrap();
+    assert!(balance > 0);
+}
+
+#[test]
+fn test_oracle_address() {
+    let mut asset1 = get_asset("uatom");
+    asset1.price_expiry = Some(30);
+    let test_env = setup_contract(asset1);
+    let modules = get_modules(&test_env);
+
+    let info: State = modules
+        .wasm
+        .query(&test_env.contract_addr, &VaultState(StateQuery::Info))
+        .unwrap();
+    let (asset1, mut config) = match info {
+        State::Info { asset1, config, .. } => (asset1, config),
+        _ => panic!("unexpected state response"),
+    };
+    assert_eq!(asset1.price_expiry, Some(30));
+
+    // accounts can't be set as the oracle
+    config.pyth_contract_address = Addr::unchecked(test_env.users[0].address());
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::Config(config.clone()))),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap_err();
+
+    config.pyth_contract_address = Addr::unchecked(test_env.contract_addr.clone());
+    execute_timelocked(&test_env, &modules, ModifyMsg::Config(config.clone()));
+
+    // once on a real oracle, the vault can't be moved back to the mock one
+    config.pyth_contract_address = Addr::unchecked("osmo1qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqmcn030");
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::Config(config))),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap_err();
+}
+
+#[test]
+fn test_numeraire() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.numeraire = Some(Numeraire::Asset0);
+    });
+    let modules = get_modules(&test_env);
+
+    // the 100 OSMO seed mints 10^18 vault tokens per OSMO
+    let nav: State = modules
+        .wasm
+        .query(&test_env.contract_addr, &VaultState(StateQuery::Nav))
+        .unwrap();
+    match nav {
+        State::Nav {
+            numeraire,
+            total_value,
+            supply,
+            ..
+        } => {
+            assert_eq!(numeraire, Numeraire::Asset0);
+            assert_eq!(supply, Uint128::new(100 * 10_u128.pow(18)));
+            // the absorbed ATOM is worth a bit over 6 OSMO
+            let osmo = Uint256::from(10_u128.pow(26));
+            assert!(total_value > osmo * Uint256::from(106_u128));
+            assert!(total_value < osmo * Uint256::from(107_u128));
+        }
+        _ => panic!("unexpected state"),
+    }
+
+    execute_joins(
+        &test_env,
+        &modules,
+        JOINS[0],
+        &"uatom".to_string(),
+        1_000_000,
+    );
+    execute_leaves(&test_env, &modules);
+
+    // an external numeraire goes through the same confidence check as the vault assets
+    let eth = get_asset("wei");
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.max_confidence_ratio = Some(Decimal::percent(1));
+        msg.numeraire = Some(Numeraire::External {
+            denom: eth.denom.clone(),
+            decimals: eth.decimals,
+            source: PriceSource::Pyth {
+                price_identifier: eth.price_identifier,
+            },
+        });
+    });
+    let modules = get_modules(&test_env);
+    let query_nav = || {
+        modules
+            .wasm
+            .query::<_, State>(&test_env.contract_addr, &VaultState(StateQuery::Nav))
+    };
+    query_nav().unwrap();
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::SetMockPrices(vec![MockPrice {
+                price_identifier: eth.price_identifier,
+                price: Uint128::new(278_558_964_008),
+                conf: Uint128::new(5_571_179_280),
+                expo: -8,
+            }])),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+    let err = query_nav().unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("confidence interval for wei is too wide"));
+}
+
+#[test]
+fn test_commission_split() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.management_fee = Some(Decimal::percent(10));
+    });
+    let modules = get_modules(&test_env);
+    let vault_denom = format!("factory/{}/BVT", test_env.contract_addr);
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::CommissionReceivers(vec![
+                CommissionReceiver {
+                    address: Addr::unchecked(test_env.users[0].address()),
+                    weight: Decimal::percent(30),
+                },
+                CommissionReceiver {
+                    address: Addr::unchecked(test_env.users[1].address()),
+                    weight: Decimal::percent(70),
+                },
+            ]))),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    // weights that don't add up to 100% are rejected
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::CommissionReceivers(vec![
+                CommissionReceiver {
+                    address: Addr::unchecked(test_env.users[0].address()),
+                    weight: Decimal::percent(30),
+                },
+            ]))),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap_err();
+
+    test_env.app.increase_time(86400 * 365);
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::AccrueFees),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    let balances = user_balance_list(&test_env, &modules, vault_denom);
+
+    assert!(balances[0] > 0);
+    assert_eq!(balances[0], (balances[0] + balances[1]) * 3 / 10);
+}
+
+#[test]
+fn test_deposit_and_withdraw_fees() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.commission = None;
+        msg.deposit_fee = Some(Decimal::percent(1));
+        msg.withdraw_fee = Some(Decimal::percent(2));
+        msg.fee_destination = Some(FeeDestination::Commission);
+    });
+    let modules = get_modules(&test_env);
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &Deposit(DepositMsg::Mint {
+                min_out: None,
+                allocation: None,
+            }),
+            &[coin(1_000_000, "uosmo")],
+            &test_env.users[0],
+        )
+        .unwrap();
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::ProcessMints),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    let commission: Vec<Coin> = modules
+        .wasm
+        .query(&test_env.contract_addr, &Rewards(RewardQuery::Commission))
+        .unwrap();
+
+    assert_eq!(commission[0], coin(10_000, "uosmo"));
+
+    let vault_denom = format!("factory/{}/BVT", test_env.contract_addr);
+    let bvt_balance = user_balance_list(&test_env, &modules, vault_denom.clone())[0];
+
+    let estimate: Vec<Coin> = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &EstimateDeposit(DepositQuery::Burn(Uint128::new(bvt_balance))),
+        )
+        .unwrap();
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &Deposit(DepositMsg::Burn {
+                address: None,
+                amount: None,
+            }),
+            &[coin(bvt_balance, vault_denom)],
+            &test_env.users[0],
+        )
+        .unwrap();
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::ProcessBurns),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    let commission: Vec<Coin> = modules
+        .wasm
+        .query(&test_env.contract_addr, &Rewards(RewardQuery::Commission))
+        .unwrap();
+
+    // the estimate is net of the 2% withdraw fee, which went to the commission
+    let fee = estimate[0].amount.u128() * 2 / 98;
+    assert!(commission[0].amount.u128().abs_diff(10_000 + fee) <= 1);
+}
+
+#[test]
+fn test_commission_timelock() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+
+    let modify_commission = |msg: ModifyMsg| {
+        modules.wasm.execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(msg)),
+            &[],
+            &test_env.admin,
+        )
+    };
+
+    // can't go over the max commission set at instantiation
+    modify_commission(ModifyMsg::Commission(Decimal::percent(21))).unwrap_err();
+
+    // increases have to wait for the commission delay
+    modify_commission(ModifyMsg::Commission(Decimal::percent(10))).unwrap();
+    modify_commission(ModifyMsg::ApplyCommission).unwrap_err();
+
+    let info: State = modules
+        .wasm
+        .query(&test_env.contract_addr, &VaultState(StateQuery::Info))
+        .unwrap();
+
+    match info {
+        State::Info {
+            commission_rate,
+            pending_commission,
+            ..
+        } => {
+            assert_eq!(commission_rate, Decimal::percent(1));
+            assert_eq!(pending_commission.unwrap().rate, Decimal::percent(10));
+        }
+        _ => panic!("unexpected state response"),
+    }
+
+    test_env.app.increase_time(86400);
+    modify_commission(ModifyMsg::ApplyCommission).unwrap();
+
+    let info: State = modules
+        .wasm
+        .query(&test_env.contract_addr, &VaultState(StateQuery::Info))
+        .unwrap();
+
+    match info {
+        State::Info {
+            commission_rate,
+            pending_commission,
+            ..
+        } => {
+            assert_eq!(commission_rate, Decimal::percent(10));
+            assert!(pending_commission.is_none());
+        }
+        _ => panic!("unexpected state response"),
+    }
+
+    // decreases apply immediately
+    modify_commission(ModifyMsg::Commission(Decimal::percent(5))).unwrap();
+    modify_commission(ModifyMsg::ApplyCommission).unwrap_err();
+}
+
+#[test]
+fn test_commission_delay() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.commission_delay = 86400 * 3;
+    });
+    let modules = get_modules(&test_env);
+
+    let query_config = || {
+        let info: State = modules
+            .wasm
+            .query(&test_env.contract_addr, &VaultState(StateQuery::Info))
+            .unwrap();
+        match info {
+            State::Info { config, .. } => config,
+            _ => panic!("unexpected state response"),
+        }
+    };
+    let modify_config = |config: Config| {
+        modules.wasm.execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::Config(Box::new(config)))),
+            &[],
+            &test_env.admin,
+        )
+    };
+
+    // holders always get at least a day of notice
+    let mut config = *query_config();
+    config.commission_delay = 0;
+    modify_config(config.clone()).unwrap_err();
+
+    // lowering the delay waits out the current one
+    config.commission_delay = 86400;
+    modify_config(config).unwrap();
+    assert_eq!(query_config().commission_delay, 86400 * 3);
+
+    let queued: Vec<QueuedChangeResponse> = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &QueuedChanges {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+    assert_eq!(queued.len(), 1);
+}
+
+#[test]
+fn test_timelock_queue() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+    let new_operator = Addr::unchecked(test_env.users[0].address());
+
+    let modify = |msg: ModifyMsg| {
+        modules.wasm.execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(msg)),
+            &[],
+            &test_env.admin,
+        )
+    };
+
+    let query_operator = || {
+        let info: State = modules
+            .wasm
+            .query(&test_env.contract_addr, &VaultState(StateQuery::Info))
+            .unwrap();
+
+        match info {
+            State::Info { operator, .. } => operator,
+            _ => panic!("unexpected state response"),
+        }
+    };
+
+    // the change is queued instead of applied
+    modify(ModifyMsg::Operator(new_operator.clone())).unwrap();
+    modify(ModifyMsg::PoolId(1)).unwrap();
+    assert_eq!(query_operator(), Addr::unchecked(test_env.admin.address()));
+
+    let queued: Vec<QueuedChangeResponse> = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &QueuedChanges {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+
+    assert_eq!(queued.len(), 2);
+    assert_eq!(queued[0].id, 0);
+    assert_eq!(
+        queued[0].change,
+        TimelockedChange::Operator(new_operator.clone())
+    );
+
+    // can't be executed before the eta
+    modify(ModifyMsg::ExecuteQueued(0)).unwrap_err();
+
+    // cancelled changes are gone for good
+    modify(ModifyMsg::CancelQueued(1)).unwrap();
+    modify(ModifyMsg::CancelQueued(1)).unwrap_err();
+
+    test_env.app.increase_time(TIMELOCK_DELAY);
+    modify(ModifyMsg::ExecuteQueued(1)).unwrap_err();
+    modify(ModifyMsg::ExecuteQueued(0)).unwrap();
+    assert_eq!(query_operator(), new_operator);
+
+    let queued: Vec<QueuedChangeResponse> = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &QueuedChanges {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+
+    assert!(queued.is_empty());
+}
+
+#[test]
+fn test_ownership_transfer() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+    let new_owner = &test_env.users[0];
+
+    let modify = |msg: ModifyMsg, sender: &SigningAccount| {
+        modules.wasm.execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(msg)),
+            &[],
+            sender,
+        )
+    };
+
+    modify(
+        ModifyMsg::TransferOwnership {
+            new_owner: Addr::unchecked(new_owner.address()),
+            expiry: None,
+        },
+        &test_env.admin,
+    )
+    .unwrap();
+
+    // only the pending owner can accept
+    modify(ModifyMsg::AcceptOwnership, &test_env.users[1]).unwrap_err();
+    modify(ModifyMsg::AcceptOwnership, new_owner).unwrap();
+
+    // the previous owner is still the operator, but can no longer perform owner actions
+    modify(
+        ModifyMsg::Operator(Addr::unchecked(test_env.users[1].address())),
+        &test_env.admin,
+    )
+    .unwrap_err();
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::ProcessMints),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    modify(ModifyMsg::RenounceOwnership, new_owner).unwrap();
+    modify(ModifyMsg::Commission(Decimal::percent(2)), new_owner).unwrap_err();
+
+    let info: State = modules
+        .wasm
+        .query(&test_env.contract_addr, &VaultState(StateQuery::Info))
+        .unwrap();
+
+    match info {
+        State::Info { owner, .. } => assert!(owner.is_none()),
+        _ => panic!("unexpected state response"),
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.circuit_breaker = Some(CircuitBreaker {
+            threshold: Decimal::percent(10),
+            window: 3600,
+        })
+    });
+    let modules = get_modules(&test_env);
+    let user = &test_env.users[0];
+
+    let execute = |msg: VaultMsg| {
+        modules.wasm.execute(
+            &test_env.contract_addr,
+            &ManageVault(msg),
+            &[],
+            &test_env.admin,
+        )
+    };
+
+    let deposit = || {
+        modules
+            .wasm
+            .execute(
+                &test_env.contract_addr,
+                &Deposit(DepositMsg::Mint {
+                    min_out: None,
+                    allocation: None,
+                }),
+                &[coin(1_000_000, "uatom"), coin(10_000_000, "uosmo")],
+                user,
+            )
+            .unwrap();
+    };
+
+    let query_pauses = || {
+        let status: State = modules
+            .wasm
+            .query(&test_env.contract_addr, &VaultState(StateQuery::Status))
+            .unwrap();
+
+        match status {
+            State::Status { paused, .. } => paused,
+            _ => panic!("unexpected state response"),
+        }
+    };
+
+    // the first processing records the price
+    deposit();
+    execute(VaultMsg::ProcessMints).unwrap();
+
+    // doubling the osmo side moves the vault price way over the threshold
+    modules
+        .bank
+        .send(
+            MsgSend {
+                from_address: test_env.admin.address(),
+                to_address: test_env.contract_addr.clone(),
+                amount: vec![coin(110_000_000, "uosmo").into()],
+            },
+            &test_env.admin,
+        )
+        .unwrap();
+    execute(VaultMsg::AbsorbDonations).unwrap();
+
+    deposit();
+    let response = execute(VaultMsg::ProcessMints).unwrap();
+    assert!(response.events.iter().any(|event| event
+        .attributes
+        .iter()
+        .any(|attribute| attribute.value == "banana_vault_circuit_breaker")));
+
+    let paused = query_pauses();
+    assert!(paused.mints && paused.burns);
+
+    // the deposit is left in the queue
+    let pending_mints: Vec<AccountResponse> = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &AccountStatus(AccountQuery::Mint(AccountQueryParams {
+                address: None,
+                start_after: None,
+                limit: None,
+            })),
+        )
+        .unwrap();
+    assert_eq!(pending_mints.len(), 1);
+
+    // resuming accepts the new price
+    execute(VaultMsg::Resume(Pauses::all())).unwrap();
+    execute(VaultMsg::ProcessMints).unwrap();
+    assert_eq!(query_pauses(), Pauses::default());
+}
+
+#[test]
+fn test_heartbeat() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.update_interval = Some(86400 * 2)
+    });
+    let modules = get_modules(&test_env);
+    let user = &test_env.users[0];
+
+    let query_unlock_in = || {
+        let status: State = modules
+            .wasm
+            .query(&test_env.contract_addr, &VaultState(StateQuery::Status))
+            .unwrap();
+
+        match status {
+            State::Status { unlock_in, .. } => unlock_in,
+            _ => panic!("unexpected state response"),
+        }
+    };
+
+    let unlock = || {
+        modules
+            .wasm
+            .execute(&test_env.contract_addr, &Unlock, &[], user)
+    };
+
+    // blocks have moved on a little since instantiation
+    let unlock_in = query_unlock_in();
+    assert!(unlock_in <= 86400 * 2 && unlock_in > 86400);
+
+    // processing by another processor leaves the dead man switch alone
+    let processor = &test_env.users[1];
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::GrantRole {
+                address: Addr::unchecked(processor.address()),
+                role: Role::Processor,
+            })),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::ProcessMints),
+            &[],
+            processor,
+        )
+        .unwrap();
+    assert!(query_unlock_in() <= unlock_in);
+
+    // processing by the operator and heartbeats push the dead man switch back
+    test_env.app.increase_time(86400);
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::ProcessMints),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+    test_env.app.increase_time(86400);
+    unlock().unwrap_err();
+
+    // only the operator can send heartbeats
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Heartbeat),
+            &[],
+            user,
+        )
+        .unwrap_err();
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Heartbeat),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+    assert!(query_unlock_in() > 86400);
+
+    test_env.app.increase_time(86400 * 2);
+    assert_eq!(query_unlock_in(), 0);
+    unlock().unwrap();
+}
+
+#[test]
+fn test_guardian_pauses() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+    let guardian = &test_env.users[0];
+    let user = &test_env.users[1];
+
+    let execute = |msg: VaultMsg, sender: &SigningAccount| {
+        modules
+            .wasm
+            .execute(&test_env.contract_addr, &ManageVault(msg), &[], sender)
+    };
+
+    let deposit = || {
+        modules.wasm.execute(
+            &test_env.contract_addr,
+            &Deposit(DepositMsg::Mint {
+                min_out: None,
+                allocation: None,
+            }),
+            &[coin(53_000_000, "uatom"), coin(500_000_000, "uosmo")],
+            user,
+        )
+    };
+
+    let query_pauses = || {
+        let status: State = modules
+            .wasm
+            .query(&test_env.contract_addr, &VaultState(StateQuery::Status))
+            .unwrap();
+
+        match status {
+            State::Status { paused, .. } => paused,
+            _ => panic!("unexpected state response"),
+        }
+    };
+
+    let mints = Pauses {
+        mints: true,
+        ..Pauses::default()
+    };
+
+    execute(VaultMsg::Pause(mints.clone()), guardian).unwrap_err();
+
+    execute(
+        VaultMsg::Modify(ModifyMsg::GrantRole {
+            address: Addr::unchecked(guardian.address()),
+            role: Role::Guardian,
+        }),
+        &test_env.admin,
+    )
+    .unwrap();
+
+    execute(VaultMsg::Pause(mints.clone()), guardian).unwrap();
+    assert_eq!(query_pauses(), mints);
+
+    // only mints are paused
+    deposit().unwrap_err();
+    execute(VaultMsg::ProcessMints, &test_env.admin).unwrap_err();
+    execute(VaultMsg::ProcessBurns, &test_env.admin).unwrap();
+
+    // the guardian can't resume, only the owner can
+    execute(VaultMsg::Resume(mints.clone()), guardian).unwrap_err();
+    execute(VaultMsg::Pause(Pauses::all()), guardian).unwrap();
+    assert_eq!(query_pauses(), Pauses::all());
+
+    execute(VaultMsg::Resume(mints), &test_env.admin).unwrap();
+    assert_eq!(
+        query_pauses(),
+        Pauses {
+            mints: false,
+            ..Pauses::all()
+        }
+    );
+    deposit().unwrap();
+}
+
+#[test]
+fn test_roles() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+    let keeper = &test_env.users[0];
+
+    let execute = |msg: VaultMsg, sender: &SigningAccount| {
+        modules
+            .wasm
+            .execute(&test_env.contract_addr, &ManageVault(msg), &[], sender)
+    };
+
+    execute(VaultMsg::ProcessMints, keeper).unwrap_err();
+
+    execute(
+        VaultMsg::Modify(ModifyMsg::GrantRole {
+            address: Addr::unchecked(keeper.address()),
+            role: Role::Processor,
+        }),
+        &test_env.admin,
+    )
+    .unwrap();
+
+    // only the owner can grant roles
+    execute(
+        VaultMsg::Modify(ModifyMsg::GrantRole {
+            address: Addr::unchecked(keeper.address()),
+            role: Role::FeeCollector,
+        }),
+        keeper,
+    )
+    .unwrap_err();
+
+    let roles: RolesResponse = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &Roles {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+
+    assert_eq!(roles.roles.len(), 1);
+    assert_eq!(roles.roles[0].roles, vec![Role::Processor]);
+
+    // the keeper can process the queues, but not touch fees or positions
+    execute(VaultMsg::ProcessMints, keeper).unwrap();
+    execute(VaultMsg::ProcessBurns, keeper).unwrap();
+    execute(VaultMsg::AccrueFees, keeper).unwrap_err();
+    execute(
+        VaultMsg::Modify(ModifyMsg::Commission(Decimal::percent(2))),
+        keeper,
+    )
+    .unwrap_err();
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManagePosition(CreatePosition {
+                lower_tick: 2000,
+                upper_tick: 3000,
+                tokens_provided: vec![coin(1, "uosmo")],
+                token_min_amount0: "0".to_string(),
+                token_min_amount1: "0".to_string(),
+                swap: None,
+            }),
+            &[],
+            keeper,
+        )
+        .unwrap_err();
+
+    execute(
+        VaultMsg::Modify(ModifyMsg::RevokeRole {
+            address: Addr::unchecked(keeper.address()),
+            role: Role::Processor,
+        }),
+        &test_env.admin,
+    )
+    .unwrap();
+
+    execute(VaultMsg::ProcessMints, keeper).unwrap_err();
+
+    // strategists act like the operator, so granting it waits out the timelock
+    let query_roles = || {
+        let roles: RolesResponse = modules
+            .wasm
+            .query(
+                &test_env.contract_addr,
+                &Roles {
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        roles.roles
+    };
+    execute(
+        VaultMsg::Modify(ModifyMsg::GrantRole {
+            address: Addr::unchecked(keeper.address()),
+            role: Role::Strategist,
+        }),
+        &test_env.admin,
+    )
+    .unwrap();
+    assert!(query_roles().is_empty());
+
+    test_env.app.increase_time(TIMELOCK_DELAY);
+    execute(
+        VaultMsg::Modify(ModifyMsg::ExecuteQueued(0)),
+        &test_env.admin,
+    )
+    .unwrap();
+    assert_eq!(query_roles()[0].roles, vec![Role::Strategist]);
+}
+
+#[test]
+fn test_whitelist_only_deposits() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.deposit_policy = Some(DepositPolicy::WhitelistOnly)
+    });
+    let modules = get_modules(&test_env);
+    let user = &test_env.users[0];
+
+    let deposit = || {
+        modules.wasm.execute(
+            &test_env.contract_addr,
+            &Deposit(DepositMsg::Mint {
+                min_out: None,
+                allocation: None,
+            }),
+            &[coin(1_000_000, "uatom"), coin(10_000_000, "uosmo")],
+            user,
+        )
+    };
+
+    let err = deposit().unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("Only whitelisted addresses can deposit"));
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::Whitelist {
+                add: Some(vec![Addr::unchecked(user.address())]),
+                remove: None,
+            })),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    deposit().unwrap();
+}
+
+#[test]
+fn test_address_caps() {
+    // $20 by default, 1 * 10^(18+8) = 1 USD
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.address_cap = Some(Uint128::new(20 * 10_u128.pow(26)))
+    });
+    let modules = get_modules(&test_env);
+    let capped = &test_env.users[0];
+    let whale = &test_env.users[1];
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::AddressCaps {
+                set: Some(vec![AddressCap {
+                    address: Addr::unchecked(whale.address()),
+                    cap: Uint128::new(100 * 10_u128.pow(26)),
+                }]),
+                remove: None,
+            })),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    let whale_cap: Option<Uint128> = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &AddressCapQuery {
+                address: Addr::unchecked(whale.address()),
+            },
+        )
+        .unwrap();
+    assert_eq!(whale_cap, Some(Uint128::new(100 * 10_u128.pow(26))));
+
+    // ~$26.7 each, the capped address asks for close to what the whole deposit would mint
+    let deposit = [coin(1_000_000, "uatom"), coin(10_000_000, "uosmo")];
+    let estimate: Vec<Coin> = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &EstimateDeposit(DepositQuery::Mint(deposit.to_vec())),
+        )
+        .unwrap();
+    let min_out = estimate[0].amount * Decimal::percent(99);
+    for (user, min_out) in [(capped, Some(min_out)), (whale, None)] {
+        modules
+            .wasm
+            .execute(
+                &test_env.contract_addr,
+                &Deposit(DepositMsg::Mint {
+                    min_out,
+                    allocation: None,
+                }),
+                &deposit,
+                user,
+            )
+            .unwrap();
+    }
+
+    let response = modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::ProcessMints),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    // only the capped address gets part of its deposit back, and its min out shrinks with it
+    let mut refunded = vec![];
+    let mut slipped = vec![];
+    let mut current_address = String::new();
+    for attribute in response
+        .events
+        .iter()
+        .filter(|event| event.ty == "wasm")
+        .flat_map(|event| event.attributes.iter())
+    {
+        match attribute.key.as_str() {
+            "address" => current_address = attribute.value.clone(),
+            "refunded" => refunded.push(current_address.clone()),
+            "slippage" => slipped.push(current_address.clone()),
+            _ => {}
+        }
+    }
+    assert!(refunded.contains(&capped.address()));
+    assert!(!refunded.contains(&whale.address()));
+    assert!(slipped.is_empty());
+}
+
+#[test]
+fn test_cap_filling() {
+    // the vault starts with ~$174.5, leaving ~$25.5 under a $200 cap. 1 * 10^(18+8) = 1 USD
+    let dollar_cap = Some(Uint128::new(200 * 10_u128.pow(26)));
+
+    for (fill_order, refund_unfilled) in [(FillOrder::FirstCome, false), (FillOrder::ProRata, true)]
+    {
+        let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+            msg.dollar_cap = dollar_cap;
+            msg.fill_order = Some(fill_order.clone());
+            msg.refund_unfilled = Some(refund_unfilled);
+        });
+        let modules = get_modules(&test_env);
+
+        // ~$26.7 each, only one of them fits
+        for user in &test_env.users[..2] {
+            modules
+                .wasm
+                .execute(
+                    &test_env.contract_addr,
+                    &Deposit(DepositMsg::Mint {
+                        min_out: None,
+                        allocation: None,
+                    }),
+                    &[coin(1_000_000, "uatom"), coin(10_000_000, "uosmo")],
+                    user,
+                )
+                .unwrap();
+        }
+
+        let response = modules
+            .wasm
+            .execute(
+                &test_env.contract_addr,
+                &ManageVault(VaultMsg::ProcessMints),
+                &[],
+                &test_env.admin,
+            )
+            .unwrap();
+
+        // wasm attribute keys emitted for each address
+        let mut keys: Vec<(String, Vec<String>)> = vec![];
+        for attribute in response
+            .events
+            .iter()
+            .filter(|event| event.ty == "wasm")
+            .flat_map(|event| event.attributes.iter())
+        {
+            match attribute.key.as_str() {
+                "address" => keys.push((attribute.value.clone(), vec![])),
+                key => {
+                    if let Some((_, address_keys)) = keys.last_mut() {
+                        address_keys.push(key.to_string())
+                    }
+                }
+            }
+        }
+        let keys_of = |user: &SigningAccount| {
+            keys.iter()
+                .find(|(address, _)| *address == user.address())
+                .map(|(_, keys)| keys.clone())
+                .unwrap()
+        };
+        let first = keys_of(&test_env.users[0]);
+        let second = keys_of(&test_env.users[1]);
+
+        match fill_order {
+            // the first depositor takes all the headroom and the rest of both deposits waits in queue
+            FillOrder::FirstCome => {
+                assert!(first.contains(&"minted".to_string()));
+                assert!(first.contains(&"queued".to_string()));
+                assert!(!second.contains(&"minted".to_string()));
+                assert!(second.contains(&"queued".to_string()));
+
+                let pending_mints: Vec<AccountResponse> = modules
+                    .wasm
+                    .query(
+                        &test_env.contract_addr,
+                        &AccountStatus(AccountQuery::Mint(AccountQueryParams {
+                            address: None,
+                            start_after: None,
+                            limit: None,
+                        })),
+                    )
+                    .unwrap();
+                assert_eq!(pending_mints.len(), 2);
+            }
+            // both get the same share minted and the rest back
+            FillOrder::ProRata => {
+                for keys in [first, second] {
+                    assert!(keys.contains(&"minted".to_string()));
+                    assert!(keys.contains(&"refunded".to_string()));
+                    assert!(!keys.contains(&"queued".to_string()));
+                }
+            }
+        }
+
+        let vault_state: State = modules
+            .wasm
+            .query(&test_env.contract_addr, &VaultState(StateQuery::Status))
+            .unwrap();
+        match vault_state {
+            State::Status { cap_reached, .. } => assert!(cap_reached),
+            _ => panic!("unexpected state"),
+        }
+
+        // whitelisted depositors still get their whole deposit minted over the cap
+        let whitelisted = &test_env.users[2];
+        modules
+            .wasm
+            .execute(
+                &test_env.contract_addr,
+                &ManageVault(VaultMsg::Modify(ModifyMsg::Whitelist {
+                    add: Some(vec![Addr::unchecked(whitelisted.address())]),
+                    remove: None,
+                })),
+                &[],
+                &test_env.admin,
+            )
+            .unwrap();
+        modules
+            .wasm
+            .execute(
+                &test_env.contract_addr,
+                &Deposit(DepositMsg::Mint {
+                    min_out: None,
+                    allocation: None,
+                }),
+                &[coin(1_000_000, "uatom"), coin(10_000_000, "uosmo")],
+                whitelisted,
+            )
+            .unwrap();
+        let response = modules
+            .wasm
+            .execute(
+                &test_env.contract_addr,
+                &ManageVault(VaultMsg::ProcessMints),
+                &[],
+                &test_env.admin,
+            )
+            .unwrap();
+        let attributes: Vec<_> = response
+            .events
+            .iter()
+            .filter(|event| event.ty == "wasm")
+            .flat_map(|event| event.attributes.iter())
+            .skip_while(|attribute| attribute.value != whitelisted.address())
+            .skip(1)
+            .take_while(|attribute| attribute.key != "address")
+            .map(|attribute| attribute.key.as_str())
+            .collect();
+        assert!(attributes.contains(&"minted"));
+        assert!(!attributes.contains(&"queued"));
+        assert!(!attributes.contains(&"refunded"));
+    }
+}
+
+#[test]
+fn test_merkle_allocations() {
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+    let user = &test_env.users[0];
+    let other = &test_env.users[1];
+
+    // $40 each, 1 * 10^(18+8) = 1 USD
+    let max_deposit = Uint128::new(40 * 10_u128.pow(26));
+    let leaf = |address: String| -> [u8; 32] {
+        Sha256::new()
+            .chain_update([0])
+            .chain_update((address.len() as u32).to_be_bytes())
+            .chain_update(address.as_bytes())
+            .chain_update(max_deposit.u128().to_be_bytes())
+            .finalize()
+            .into()
+    };
+    let user_leaf = leaf(user.address());
+    let other_leaf = leaf(other.address());
+
+    let mut pair = [user_leaf, other_leaf];
+    pair.sort_unstable();
+    let root: [u8; 32] = Sha256::new()
+        .chain_update([1])
+        .chain_update(pair.concat())
+        .finalize()
+        .into();
+
+    modules
+        .wasm
+        .execute(
+            &test_env.contract_addr,
+            &ManageVault(VaultMsg::Modify(ModifyMsg::DepositMerkleRoot(Some(
+                hex::encode(root),
+            )))),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    // ~$26.7 per deposit
+    let deposit = |allocation: Option<Allocation>| {
+        modules.wasm.execute(
+            &test_env.contract_addr,
+            &Deposit(DepositMsg::Mint {
+                min_out: None,
+                allocation,
+            }),
+            &[coin(1_000_000, "uatom"), coin(10_000_000, "uosmo")],
+            user,
+        )
+    };
+
+    deposit(None).unwrap_err();
+
+    // the proof doesn't match another allocation
+    deposit(Some(Allocation {
+        max_deposit: max_deposit + Uint128::one(),
+        proof: vec![hex::encode(other_leaf)],
+    }))
+    .unwrap_err();
+
+    let allocation = Allocation {
+        max_deposit,
+        proof: vec![hex::encode(other_leaf)],
+    };
+
+    deposit(Some(allocation.clone())).unwrap();
+
+    let deposited: Uint128 = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &DepositedDollars {
+                address: Addr::unchecked(user.address()),
+            },
+        )
+        .unwrap();
+    assert!(deposited > Uint128::new(26 * 10_u128.pow(26)));
+
+    // a second deposit would go over the allocation
+    let err = deposit(Some(allocation)).unwrap_err();
+    assert!(err.to_string().contains("Deposit exceeds allocation"));
+
+    // refunded deposits give the allocation back
+    modules
+        .wasm
+        .execute(&test_env.contract_addr, &Unlock, &[], &test_env.admin)
+        .unwrap();
+    let deposited: Uint128 = modules
+        .wasm
+        .query(
+            &test_env.contract_addr,
+            &DepositedDollars {
+                address: Addr::unchecked(user.address()),
+            },
+        )
+        .unwrap();
+    assert!(deposited.is_zero());
+}
+
+#[test]
+fn test_denom_metadata() {
+    let test_env = setup_contract_with(get_asset("uatom"), |msg| {
+        msg.subdenom = Some("bananas".to_string());
+        msg.metadata = Some(Metadata {
+            name: "Banana Vault".to_string(),
+            description: None,
+            image: None,
+            symbol: Some("BANANA".to_string()),
+            exponent: None,
+        });
+    });
+    let modules = get_modules(&test_env);
+    let vault_denom = format!("factory/{}/bananas", test_env.contract_addr);
+
+    let query_metadata = || {
+        test_env
+            .app
+            .query::<_, QueryDenomMetadataResponse>(
+                "/cosmos.bank.v1beta1.Query/DenomMetadata",
+                &QueryDenomMetadataRequest {
+                    denom: vault_denom.clone(),
+                },
+            )
+            .unwrap()
+            .metadata
+            .unwrap()
+    };
+
+    let metadata = query_metadata();
+    assert_eq!(metadata.base, vault_denom);
+    assert_eq!(metadata.display, "BANANA");
+    assert_eq!(metadata.name, "Banana Vault");
+    assert_eq!(metadata.denom_units[1].exponent, 18);
+
+    let info: State = modules
+        .wasm
+        .query(&test_env.contract_addr, &VaultState(StateQuery::Info))
+        .unwrap();
+
+    let mut config = match info {
+        State::Info { config, .. } => config,
+        _ => panic!("unexpected state response"),
+    };
+
+    // changing the config publishes the new metadata
+    config.metadata = Some(Metadata {
+        name: "Banana Vault".to_string(),
+        description: Some("ATOM/OSMO".to_string()),
+        image: None,
+        symbol: None,
+        exponent: Some(6),
+    });
+
+    execute_timelocked(&test_env, &modules, ModifyMsg::Config(config.clone()));
+
+    let metadata = query_metadata();
+    assert_eq!(metadata.symbol, "bananas");
+    assert_eq!(metadata.description, "ATOM/OSMO");
+    assert_eq!(metadata.denom_units[1].exponent, 6);
+
+    // metadata the bank module would reject can't be queued
+    for (name, symbol, exponent) in [
+        ("Banana Vault", Some("$BANANA"), None),
+        ("Banana Vault", Some("b"), None),
+        ("Banana Vault", None, Some(0)),
+        (" ", None, None),
+    ] {
+        let mut invalid = config.clone();
+        invalid.metadata = Some(Metadata {
+            name: name.to_string(),
+            description: None,
+            image: None,
+            symbol: symbol.map(str::to_string),
+            exponent,
+        });
+        modules
+            .wasm
+            .execute(
+                &test_env.contract_addr,
+                &ManageVault(VaultMsg::Modify(ModifyMsg::Config(invalid))),
+                &[],
+                &test_env.admin,
+            )
+            .unwrap_err();
+    }
+}
+
+#[test]
+#[should_panic(expected = "Invalid subdenom")]
+fn test_invalid_subdenom() {
+    setup_contract_with(get_asset("uatom"), |msg| {
+        msg.subdenom = Some("banana vault".to_string());
+    });
+}
+
+#[test]
+#[should_panic(expected = "Invalid subdenom")]
+fn test_subdenom_too_long() {
+    setup_contract_with(get_asset("uatom"), |msg| {
+        msg.subdenom = Some("b".repeat(45));
+    });
+}
+
+#[test]
+fn test_migrate_from_v0_5_0() {
+    // messages as the v0.5.0 contract expects them
+    #[cw_serde]
+    struct InstantiateMsgV0_5_0 {
+        metadata: Option<Metadata>,
+        asset0: VaultAsset,
+        asset1: VaultAsset,
+        min_asset0: Uint128,
+        min_asset1: Uint128,
+        price_expiry: u64,
+        pool_id: u64,
+        min_redemption: Option<Uint128>,
+        dollar_cap: Option<Uint128>,
+        commission: Option<Decimal>,
+        commission_receiver: Option<Addr>,
+        env: Option<Environment>,
+        operator: Addr,
+    }
+
+    #[cw_serde]
+    enum ExecuteMsgV0_5_0 {
+        ManageVault(VaultMsgV0_5_0),
+        Deposit(DepositMsgV0_5_0),
+    }
+
+    #[cw_serde]
+    enum VaultMsgV0_5_0 {
+        ProcessMints,
+        Halt,
+    }
+
+    #[cw_serde]
+    enum DepositMsgV0_5_0 {
+        Mint { min_out: Option<Uint128> },
+    }
+
+    let test_env = setup_contract(get_asset("uatom"));
+    let modules = get_modules(&test_env);
+    let user = &test_env.users[0];
+    let receiver = Addr::unchecked(test_env.users[1].address());
+
+    let old_wasm = std::fs::read("./artifacts/banana_vault_v0.5.0.wasm")
+        .expect("could not read the v0.5.0 wasm - run scripts/build_old_release.sh first");
+    let old_code_id = modules
+        .wasm
+        .store_code(&old_wasm, None, &test_env.admin)
+        .unwrap()
+        .data
+        .code_id;
+
+    let vault = modules
+        .wasm
+        .instantiate(
+            old_code_id,
+            &InstantiateMsgV0_5_0 {
+                metadata: None,
+                asset0: VaultAsset {
+                    denom: "uosmo".to_string(),
+                    price_identifier: PriceIdentifier::from_hex(
+                        "5867f5683c757393a0670ef0f701490950fe93fdb006d181c8265a831ac0c5c6",
+                    )
+                    .unwrap(),
+                    decimals: 6,
+                    oracle: None,
+                    fallback: None,
+                    price_expiry: None,
+                },
+                asset1: get_asset("uatom"),
+                min_asset0: 10000_u64.into(),
+                min_asset1: 10000_u64.into(),
+                price_expiry: 60,
+                pool_id: 1,
+                min_redemption: None,
+                dollar_cap: None,
+                commission: Some(Decimal::percent(1)),
+                commission_receiver: Some(receiver.clone()),
+                env: Some(Environment::Testtube),
+                operator: Addr::unchecked(test_env.admin.address()),
+            },
+            Some(&test_env.admin.address()),
+            Some("bv"),
+            &[coin(100_000_000, "uosmo")],
+            &test_env.admin,
+        )
+        .unwrap()
+        .data
+        .address;
+
+    // populate some state with the old code
+    modules
+        .wasm
+        .execute(
+            &vault,
+            &ExecuteMsgV0_5_0::Deposit(DepositMsgV0_5_0::Mint { min_out: None }),
+            &[coin(1_000_000, "uatom"), coin(10_000_000, "uosmo")],
+            user,
+        )
+        .unwrap();
+    modules
+        .wasm
+        .execute(
+            &vault,
+            &ExecuteMsgV0_5_0::ManageVault(VaultMsgV0_5_0::ProcessMints),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+    modules
+        .wasm
+        .execute(
+            &vault,
+            &ExecuteMsgV0_5_0::ManageVault(VaultMsgV0_5_0::Halt),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+
+    let supply_before = match modules
+        .wasm
+        .query::<_, State>(&vault, &VaultState(StateQuery::Status))
+        .unwrap()
+    {
+        State::Status { supply, .. } => supply,
+        _ => panic!("unexpected state response"),
+    };
+
+    let new_wasm = std::fs::read("./target/wasm32-unknown-unknown/release/banana_vault.wasm")
+        .expect("could not read wasm file - run `cargo wasm` first");
+    let new_code_id = modules
+        .wasm
+        .store_code(&new_wasm, None, &test_env.admin)
+        .unwrap()
+        .data
+        .code_id;
+
+    let migrate = |msg: &MigrateMsg| {
+        test_env.app.execute::<_, MsgMigrateContractResponse>(
+            MsgMigrateContract {
+                sender: test_env.admin.address(),
+                contract: vault.clone(),
+                code_id: new_code_id,
+                msg: to_json_vec(msg).unwrap(),
+            },
+            MsgMigrateContract::TYPE_URL,
+            &test_env.admin,
+        )
+    };
+
+    // the rate is above the requested max commission
+    migrate(&MigrateMsg {
+        max_commission: Some(Decimal::permille(5)),
+        ..MigrateMsg::default()
+    })
+    .unwrap_err();
+
+    migrate(&MigrateMsg {
+        max_commission: Some(Decimal::percent(5)),
+        update_interval: Some(86400 * 7),
+        ..MigrateMsg::default()
+    })
+    .unwrap();
+
+    match modules
+        .wasm
+        .query::<_, State>(&vault, &VaultState(StateQuery::Info))
+        .unwrap()
+    {
+        State::Info {
+            max_commission,
+            commission_receivers,
+            config,
+            ..
+        } => {
+            assert_eq!(max_commission, Decimal::percent(5));
+            assert_eq!(
+                commission_receivers,
+                vec![CommissionReceiver {
+                    address: receiver,
+                    weight: Decimal::one(),
+                }]
+            );
+            assert_eq!(config.update_interval, 86400 * 7);
+            assert_eq!(config.management_fee, None);
+        }
+        _ => panic!("unexpected state response"),
+    }
+
+    match modules
+        .wasm
+        .query::<_, State>(&vault, &VaultState(StateQuery::Status))
+        .unwrap()
+    {
+        State::Status { paused, supply, .. } => {
+            assert_eq!(supply, supply_before);
+            assert!(paused.mints && paused.burns);
+            assert!(!paused.positions && !paused.compounding);
+        }
+        _ => panic!("unexpected state response"),
+    }
+
+    // migrating to the same version again leaves the state alone
+    migrate(&MigrateMsg::default()).unwrap();
+
+    // the mock oracle quotes the seed prices: 110 OSMO at ~$1.64 and 1 ATOM at ~$10.31
+    match modules
+        .wasm
+        .query::<_, State>(&vault, &VaultState(StateQuery::Nav))
+        .unwrap()
+    {
+        State::Nav { total_value, .. } => {
+            let dollar = Uint256::from(10_u128.pow(26));
+            assert!(total_value > dollar * Uint256::from(190_u128));
+            assert!(total_value < dollar * Uint256::from(192_u128));
+        }
+        _ => panic!("unexpected state response"),
+    }
+
+    modules
+        .wasm
+        .execute(
+            &vault,
+            &ManageVault(VaultMsg::Resume(Pauses::all())),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+    modules
+        .wasm
+        .execute(
+            &vault,
+            &Deposit(DepositMsg::Mint {
+                min_out: None,
+                allocation: None,
+            }),
+            &[coin(1_000_000, "uatom"), coin(10_000_000, "uosmo")],
+            user,
+        )
+        .unwrap();
+    modules
+        .wasm
+        .execute(
+            &vault,
+            &ManageVault(VaultMsg::ProcessMints),
+            &[],
+            &test_env.admin,
+        )
+        .unwrap();
+}